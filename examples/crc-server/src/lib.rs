@@ -10,8 +10,10 @@ mod messages_capnp {
     include!(concat!(env!("OUT_DIR"), "/messages_capnp.rs"));
 }
 
+use std::collections::HashMap;
 use std::fmt;
 use std::io::{
+    self,
     Write,
 };
 use std::net::{
@@ -28,17 +30,15 @@ use capnp::serialize::{
     read_message,
     write_message,
 };
-use capnp_nonblock::MessageStream;
+use capnp_nonblock::{MessageStream, TypedBuilder, TypedMessageStream};
 use crc::crc32;
-use mio::tcp::TcpListener;
+use mio::net::TcpListener;
 use mio::{
-    EventLoop,
-    EventSet,
-    Handler,
-    PollOpt,
+    Events,
+    Interest,
+    Poll,
     Token,
 };
-use mio::util::Slab;
 
 use messages_capnp::{
     crc_request,
@@ -62,41 +62,47 @@ pub fn checksum(server_addr: &SocketAddr, data: &[u8]) -> Result<u32> {
     return Ok(reader.get_crc());
 }
 
-const LISTENER: Token = Token(10);
-const INITIAL: Token = Token(1);
+const LISTENER: Token = Token(0);
 
 struct Connection {
-    stream: MessageStream<mio::tcp::TcpStream>,
+    stream: TypedMessageStream<mio::net::TcpStream, crc_request::Owned, crc_response::Owned>,
     token: Token,
 }
 
 impl Connection {
 
-    fn new(tcp_stream: mio::tcp::TcpStream) -> Connection {
+    fn new(tcp_stream: mio::net::TcpStream, token: Token) -> Connection {
         Connection {
-            stream: MessageStream::new(tcp_stream, ReaderOptions::new()),
-            token: INITIAL,
+            stream: TypedMessageStream::new(MessageStream::new(tcp_stream, ReaderOptions::new())),
+            token: token,
         }
     }
 
-    fn set_token(&mut self, token: Token) {
-        assert_eq!(self.token, INITIAL);
-        self.token = token;
-    }
-
+    /// Reads requests and queues their responses. Reading stops while the
+    /// outbound queue is above its high watermark, so that a client which does
+    /// not read its responses cannot make the server buffer without bound.
+    ///
+    /// Once the client has finished sending requests, the connection is closed
+    /// for writing, so that it is shut down after the last response is written.
     fn readable(&mut self) -> Result<()> {
-        while let Some(message) = try!(self.stream.read_message()) {
-            let data = try!(try!(message.get_root::<crc_request::Reader>()).get_data());
+        while !self.stream.get_ref().is_above_high_watermark() {
+            let message = match try!(self.stream.read_message()) {
+                Some(message) => message,
+                None => break,
+            };
+            let data = try!(message.get().get_data());
             let crc = crc32::checksum_castagnoli(data);
             info!("computing checksum of '{:?}' -> 0x{:X}", data, crc);
 
-            let mut response = Builder::new_default();
-            {
-                let mut builder = response.init_root::<crc_response::Builder>();
-                builder.set_crc(crc);
-            }
+            let mut response = TypedBuilder::<crc_response::Owned>::new_default();
+            response.init_root().set_crc(crc);
 
-            try!(self.stream.write_message(response));
+            try!(self.stream.write_message(response).map_err(io::Error::from));
+        }
+
+        if self.stream.get_ref().is_closed() && !self.stream.get_ref().is_write_closed() {
+            self.stream.get_mut().close_write();
+            try!(self.stream.write());
         }
         Ok(())
     }
@@ -107,35 +113,38 @@ impl Connection {
         Ok(())
     }
 
-    fn register(&mut self, event_loop: &mut EventLoop<CrcServer>) -> Result<()> {
+    /// Returns `true` once every request has been read, and the write half has
+    /// been shut down after the last response.
+    fn is_finished(&self) -> bool {
+        self.stream.get_ref().is_closed() && self.stream.get_ref().is_shut_down()
+    }
+
+    fn interest(&self) -> Interest {
+        if self.stream.get_ref().wants_write() {
+            Interest::READABLE | Interest::WRITABLE
+        } else {
+            Interest::READABLE
+        }
+    }
+
+    fn register(&mut self, poll: &Poll) -> Result<()> {
         trace!("registering connection {:?}", self);
-        assert!(self.token != INITIAL);
-        let event_set = EventSet::all() - EventSet::writable();
-        try!(event_loop.register_opt(self.stream.inner(),
-                                     self.token,
-                                     event_set,
-                                     PollOpt::edge() | PollOpt::oneshot()));
+        let interest = self.interest();
+        try!(poll.registry().register(self.stream.get_mut().inner_mut(), self.token, interest));
         Ok(())
     }
 
-    fn reregister(&mut self, event_loop: &mut EventLoop<CrcServer>) -> Result<()> {
+    fn reregister(&mut self, poll: &Poll) -> Result<()> {
         trace!("reregistering connection {:?}", self);
-        assert!(self.token != INITIAL);
-        let mut event_set = EventSet::all();
-        if self.stream.outbound_queue_len() == 0 {
-            event_set = event_set - EventSet::writable()
-        };
-        try!(event_loop.reregister(self.stream.inner(),
-                                   self.token,
-                                   event_set,
-                                   PollOpt::edge() | PollOpt::oneshot()));
+        let interest = self.interest();
+        try!(poll.registry().reregister(self.stream.get_mut().inner_mut(), self.token, interest));
         Ok(())
     }
 }
 
 impl fmt::Debug for Connection {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self.stream.inner().peer_addr() {
+        match self.stream.get_ref().inner().peer_addr() {
             Ok(addr) => write!(f, "Connection({})", addr),
             Err(_) => write!(f, "Connection(<unknown>)"),
         }
@@ -143,88 +152,95 @@ impl fmt::Debug for Connection {
 }
 
 pub struct CrcServer {
+    poll: Poll,
     listener: TcpListener,
-    connections: Slab<Connection>,
+    connections: HashMap<Token, Connection>,
+    next_token: usize,
 }
 
 impl CrcServer {
 
     pub fn run(addr: SocketAddr) -> Result<()> {
-        let mut event_loop = try!(EventLoop::<CrcServer>::new());
-        let listener = try!(TcpListener::bind(&addr));
-        try!(event_loop.register(&listener, LISTENER));
+        let poll = try!(Poll::new());
+        let mut listener = try!(TcpListener::bind(addr));
+        try!(poll.registry().register(&mut listener, LISTENER, Interest::READABLE));
 
         let mut server = CrcServer {
+            poll: poll,
             listener: listener,
-            connections: Slab::new_starting_at(Token(11), 128),
+            connections: HashMap::new(),
+            next_token: 1,
         };
 
-        event_loop.run(&mut server).map_err(From::from)
+        let mut events = Events::with_capacity(128);
+        loop {
+            try!(server.poll.poll(&mut events, None));
+            for event in events.iter() {
+                server.ready(event);
+            }
+        }
     }
 
-    fn accept_connection(&mut self, event_loop: &mut EventLoop<CrcServer>) -> Result<()> {
-        while let Some(stream) = try!(self.listener.accept()) {
-            let token = match self.connections.insert(Connection::new(stream)) {
-                Ok(token) => token,
-                Err(conn) => {
-                    warn!("connection limit reached; dropping connection {:?}", conn);
-                    return Ok(());
-                }
+    fn accept_connection(&mut self) -> Result<()> {
+        loop {
+            let stream = match self.listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(ref error) if error.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(error) => return Err(From::from(error)),
             };
+            let token = Token(self.next_token);
+            self.next_token += 1;
 
-            self.connections[token].set_token(token);
-            match self.connections[token].register(event_loop) {
-                Ok(_) => info!("new connection registered: {:?}", self.connections[token]),
-                Err(error) => {
-                    self.reset_connection(token);
-                    return Err(error);
-                },
-            }
+            let mut connection = Connection::new(stream, token);
+            try!(connection.register(&self.poll));
+            info!("new connection registered: {:?}", connection);
+            self.connections.insert(token, connection);
         }
-        Ok(())
     }
 
-    fn connection_ready(&mut self,
-                        event_loop: &mut EventLoop<CrcServer>,
-                        token: Token,
-                        events: EventSet)
-                        -> Result<()> {
-        let connection = &mut self.connections[token];
-        trace!("connection ready: {:?}, events: {:?}", connection, events);
-        if events.is_readable() {
+    fn connection_ready(&mut self, token: Token, event: &mio::event::Event) -> Result<()> {
+        let connection = self.connections.get_mut(&token).expect("unable to find connection");
+        trace!("connection ready: {:?}, event: {:?}", connection, event);
+        if event.is_readable() {
             try!(connection.readable());
         }
 
-        if events.is_writable() {
+        if event.is_writable() {
             try!(connection.writable());
+
+            // Resume reading requests once the queued responses have drained.
+            if connection.stream.get_ref().is_below_low_watermark() {
+                try!(connection.readable());
+            }
         }
 
-        connection.reregister(event_loop)
+        connection.reregister(&self.poll)
     }
 
     fn reset_connection(&mut self, token: Token) {
-        let connection = self.connections.remove(token).expect("unable to find connection");
+        let connection = self.connections.remove(&token).expect("unable to find connection");
         info!("connection reset: {:?}", connection);
     }
-}
 
-impl Handler for CrcServer {
-    type Timeout = ();
-    type Message = ();
-    fn ready(&mut self, event_loop: &mut EventLoop<CrcServer>, token: Token, events: EventSet) {
-
-        if events.is_hup() || events.is_error() {
-            assert!(token != LISTENER, "unexpected error event on listener socket");
-            self.reset_connection(token);
-        } else if token == LISTENER {
-            self.accept_connection(event_loop)
+    fn ready(&mut self, event: &mio::event::Event) {
+        let token = event.token();
+        if token == LISTENER {
+            self.accept_connection()
                 .unwrap_or_else(|error| warn!("unable to accept connection: {}", error));
+        } else if event.is_error() {
+            self.reset_connection(token);
         } else {
-            self.connection_ready(event_loop, token, events)
-                .unwrap_or_else(|error| {
-                    warn!("{:?}: connection error: {}", self.connections[token], error);
+            match self.connection_ready(token, event) {
+                Ok(()) if self.connections[&token].is_finished() => {
+                    let connection = self.connections.remove(&token).expect("unable to find connection");
+                    info!("connection closed: {:?}", connection);
+                },
+                Ok(()) => (),
+                Err(error) => {
+                    warn!("{:?}: connection error: {}", self.connections[&token], error);
                     self.reset_connection(token);
-                });
+                },
+            }
         }
     }
 }
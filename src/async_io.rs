@@ -0,0 +1,241 @@
+//! An adapter which reads and writes messages over Tokio's `AsyncRead` and
+//! `AsyncWrite` streams.
+//!
+//! The adapter reuses the incremental reading and writing of `MessageStream`
+//! by presenting the asynchronous stream to it as a non-blocking stream, which
+//! returns `WouldBlock` when the asynchronous stream is not ready.
+
+use std::fmt;
+use std::io::{self, IoSlice};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use capnp::message::{Builder, HeapAllocator, Reader};
+use futures_core::Stream;
+use futures_sink::Sink;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use {MessageStream, OutboundMessage, Segments};
+
+/// Wraps a `MessageStream` over an asynchronous stream, and implements `Stream`
+/// for inbound messages and `Sink` for outbound messages.
+///
+/// Messages sent to the sink are queued, and written to the stream when the
+/// sink is flushed. The sink is not ready while the outbound queue is full,
+/// and once it is ready, the next message is always queued. Closing the sink
+/// drains the outbound queue before shutting down the stream.
+///
+/// The stream of inbound messages ends after the first error which poisons the
+/// read half, and continues after errors which do not, such as skipped
+/// messages.
+pub struct AsyncMessageStream<S, M=Builder<HeapAllocator>> {
+    stream: MessageStream<S, M>,
+}
+
+impl <S, M> AsyncMessageStream<S, M> {
+
+    /// Creates a new `AsyncMessageStream` wrapping the provided message stream.
+    pub fn new(stream: MessageStream<S, M>) -> AsyncMessageStream<S, M> {
+        AsyncMessageStream { stream: stream }
+    }
+
+    /// Returns the wrapped message stream.
+    pub fn get_ref(&self) -> &MessageStream<S, M> {
+        &self.stream
+    }
+
+    /// Returns the wrapped message stream.
+    pub fn get_mut(&mut self) -> &mut MessageStream<S, M> {
+        &mut self.stream
+    }
+
+    /// Unwraps the message stream.
+    pub fn into_inner(self) -> MessageStream<S, M> {
+        self.stream
+    }
+}
+
+// The wrapped stream is never pinned in place, so the adapter may be moved
+// regardless of the message type.
+impl <S, M> Unpin for AsyncMessageStream<S, M> where S: Unpin {}
+
+impl <S, M> Stream for AsyncMessageStream<S, M> where S: AsyncRead + Unpin {
+    type Item = io::Result<Reader<Segments>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let MessageStream { ref mut inner, ref mut decoder, .. } = self.get_mut().stream;
+        // The fatal error which poisoned the decoder has already been returned.
+        if decoder.is_poisoned() {
+            return Poll::Ready(None);
+        }
+        // Unless the stream has been closed, `read_message` only returns `None`
+        // when the stream returns `WouldBlock`, which means the stream has
+        // registered the task for wakeup.
+        match decoder.read_message(&mut Compat { inner: inner, cx: cx }) {
+            Ok(Some(message)) => Poll::Ready(Some(Ok(message))),
+            Ok(None) if decoder.is_closed() => Poll::Ready(None),
+            Ok(None) => Poll::Pending,
+            Err(error) => Poll::Ready(Some(Err(error))),
+        }
+    }
+}
+
+impl <S, M> Sink<M> for AsyncMessageStream<S, M>
+where S: AsyncWrite + Unpin, M: OutboundMessage {
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let MessageStream { ref mut inner, ref mut encoder, .. } = self.get_mut().stream;
+        if !encoder.is_outbound_queue_full() {
+            return Poll::Ready(Ok(()));
+        }
+        if let Err(error) = encoder.write(&mut Compat { inner: inner, cx: cx }) {
+            return Poll::Ready(Err(error));
+        }
+        // `write` only returns with queued messages when the stream returns
+        // `WouldBlock`, which means the stream has registered the task for
+        // wakeup.
+        if encoder.is_outbound_queue_full() {
+            return Poll::Pending;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, message: M) -> io::Result<()> {
+        let encoder = &mut self.get_mut().stream.encoder;
+        match encoder.queue_message(message) {
+            Ok(_) => Ok(()),
+            Err(_) if encoder.is_closed() => {
+                Err(io::Error::new(io::ErrorKind::BrokenPipe, "stream is closed for writing"))
+            },
+            Err(_) => Err(io::Error::other("outbound queue is full")),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let MessageStream { ref mut inner, ref mut encoder, .. } = self.get_mut().stream;
+        if let Err(error) = encoder.write(&mut Compat { inner: inner, cx: cx }) {
+            return Poll::Ready(Err(error));
+        }
+        // `write` only returns with queued messages when the stream returns
+        // `WouldBlock`, which means the stream has registered the task for
+        // wakeup.
+        if encoder.outbound_queue_len() > 0 {
+            return Poll::Pending;
+        }
+        Pin::new(inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.stream.encoder.close();
+        match self.as_mut().poll_flush(cx) {
+            Poll::Ready(Ok(())) => (),
+            other => return other,
+        }
+        Pin::new(&mut self.get_mut().stream.inner).poll_shutdown(cx)
+    }
+}
+
+impl <S, M> fmt::Debug for AsyncMessageStream<S, M> where S: fmt::Debug {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "AsyncMessageStream {{ stream: {:?} }}", self.stream)
+    }
+}
+
+/// Presents an asynchronous stream as a non-blocking `io::Read` and
+/// `io::Write` stream within the context of a task.
+struct Compat<'a, 'b: 'a, S: 'a> {
+    inner: &'a mut S,
+    cx: &'a mut Context<'b>,
+}
+
+fn would_block<T>(poll: Poll<io::Result<T>>) -> io::Result<T> {
+    match poll {
+        Poll::Ready(result) => result,
+        Poll::Pending => Err(io::Error::new(io::ErrorKind::WouldBlock, "stream is not ready")),
+    }
+}
+
+impl <'a, 'b, S> io::Read for Compat<'a, 'b, S> where S: AsyncRead + Unpin {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut buf = ReadBuf::new(buf);
+        try!(would_block(Pin::new(&mut *self.inner).poll_read(self.cx, &mut buf)));
+        Ok(buf.filled().len())
+    }
+}
+
+impl <'a, 'b, S> io::Write for Compat<'a, 'b, S> where S: AsyncWrite + Unpin {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        would_block(Pin::new(&mut *self.inner).poll_write(self.cx, buf))
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+        would_block(Pin::new(&mut *self.inner).poll_write_vectored(self.cx, bufs))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        would_block(Pin::new(&mut *self.inner).poll_flush(self.cx))
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use std::io;
+
+    use capnp::{data, message};
+    use futures::executor::block_on;
+    use futures::{future, stream, StreamExt};
+    use tokio::io::{duplex, AsyncWriteExt};
+
+    use quickcheck::{quickcheck, TestResult};
+
+    use super::AsyncMessageStream;
+    use {MessageStream, QueueOptions};
+    use test_utils::data_message;
+
+    #[test]
+    fn check_round_trip_async() {
+        fn round_trip(messages: Vec<Vec<u8>>, capacity: usize, max_bytes: usize) -> TestResult {
+            if capacity == 0 { return TestResult::discard(); }
+            let (client, server) = duplex(capacity);
+            // Once the sink is ready, a message is sent even if it takes the
+            // queue past its byte limit.
+            let options = *QueueOptions::new().max_bytes(max_bytes % 256);
+            let client =
+                AsyncMessageStream::new(MessageStream::new(client, message::ReaderOptions::new())
+                                            .with_queue_options(options));
+            let server =
+                AsyncMessageStream::<_, ()>::new(MessageStream::new(server,
+                                                                        message::ReaderOptions::new()));
+
+            // The duplex stream holds at most `capacity` bytes, so the writer
+            // and reader must make progress concurrently. Forwarding closes the
+            // client once all messages are sent, which ends the server stream.
+            let outbound = stream::iter(messages.iter().map(|data| Ok(data_message(data))));
+            let (sent, received) = block_on(future::join(outbound.forward(client),
+                                                         server.collect::<Vec<_>>()));
+            sent.unwrap();
+
+            TestResult::from_bool(messages.len() == received.len() &&
+                                  messages.iter().zip(received).all(|(data, message)| {
+                &data[..] == message.unwrap().get_root::<data::Reader>().unwrap()
+            }))
+        }
+
+        quickcheck(round_trip as fn(Vec<Vec<u8>>, usize, usize) -> TestResult);
+    }
+
+    #[test]
+    fn test_stream_ends_after_fatal_error() {
+        let (mut client, server) = duplex(64);
+        let server =
+            AsyncMessageStream::<_, ()>::new(MessageStream::new(server, message::ReaderOptions::new()));
+
+        // A segment table with too many segments poisons the read half.
+        block_on(client.write_all(&[255, 1, 0, 0, 0, 0, 0, 0])).unwrap();
+        let received = block_on(server.collect::<Vec<_>>());
+        assert_eq!(1, received.len());
+        assert_eq!(io::ErrorKind::InvalidData, received[0].as_ref().err().unwrap().kind());
+    }
+}
@@ -1,10 +1,92 @@
-use alloc::heap::{Heap, Alloc, Layout};
-use std::{cmp, io, mem, ops, ptr, slice};
+use std::{cmp, fmt, io, mem, ops, ptr, slice};
+#[cfg(not(feature = "sync"))]
+use std::cell::{RefCell, RefMut};
+#[cfg(not(feature = "sync"))]
+use std::rc::Rc;
+#[cfg(feature = "sync")]
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Default buffer size.
+const BUF_SIZE: usize = 4096;
 
-use std::io::Write;
+/// Default maximum size of replacement buffers.
+const MAX_BUF_SIZE: usize = 1024 * 1024;
 
-/// Default buffer size. Perhaps this should be tunable.
-const BUF_SIZE: usize = 4096;
+/// The number of recently read messages which an adaptively sized buffer
+/// should be able to hold.
+const ADAPTIVE_MESSAGES: usize = 16;
+
+/// Options which control the sizes of the buffers allocated to hold inbound
+/// messages.
+///
+/// Buffers are allocated in chunks, which are loaned out to the messages read
+/// from them. When a chunk has insufficient capacity for the message being
+/// read, it is replaced with a new chunk. Message segments are never split
+/// across chunks, so a replacement chunk may exceed `max_size` if necessary to
+/// hold a large segment.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BufferOptions {
+    /// The size of the first buffer. Defaults to 4KiB.
+    pub initial_size: usize,
+
+    /// The minimum size of replacement buffers. Defaults to 4KiB.
+    pub min_size: usize,
+
+    /// The maximum size of replacement buffers. Defaults to 1MiB.
+    pub max_size: usize,
+
+    /// Whether replacement buffers are sized to hold a number of messages of
+    /// the size of recently read messages. Defaults to `false`.
+    pub adaptive: bool,
+}
+
+impl Default for BufferOptions {
+    fn default() -> BufferOptions {
+        BufferOptions {
+            initial_size: BUF_SIZE,
+            min_size: BUF_SIZE,
+            max_size: MAX_BUF_SIZE,
+            adaptive: false,
+        }
+    }
+}
+
+impl BufferOptions {
+    pub fn new() -> BufferOptions {
+        BufferOptions::default()
+    }
+
+    pub fn initial_size(&mut self, value: usize) -> &mut BufferOptions {
+        self.initial_size = value;
+        self
+    }
+
+    pub fn min_size(&mut self, value: usize) -> &mut BufferOptions {
+        self.min_size = value;
+        self
+    }
+
+    pub fn max_size(&mut self, value: usize) -> &mut BufferOptions {
+        self.max_size = value;
+        self
+    }
+
+    pub fn adaptive(&mut self, value: bool) -> &mut BufferOptions {
+        self.adaptive = value;
+        self
+    }
+
+    /// Returns the size of a replacement buffer which must hold at least
+    /// `amount` bytes, given the average length of recently read messages.
+    pub fn replacement_size(&self, amount: usize, average_message_len: usize) -> usize {
+        let target = if self.adaptive {
+            average_message_len.saturating_mul(ADAPTIVE_MESSAGES)
+        } else {
+            0
+        };
+        cmp::max(amount, cmp::min(self.max_size, cmp::max(self.min_size, target)))
+    }
+}
 
 /// A reference counted slab allocator.
 ///
@@ -12,8 +94,9 @@ const BUF_SIZE: usize = 4096;
 /// written. The buffer is fixed size, and append only. The bytes may be shared
 /// as owned `Buf` instances.
 ///
-/// The reference counting mechanism of `MutBuf` is not threadsafe, so instances
-/// may not be shared or sent across thread boundaries.
+/// The reference counting mechanism of `MutBuf` is not threadsafe unless the
+/// `sync` feature is enabled, so instances may not be shared or sent across
+/// thread boundaries.
 pub struct MutBuf {
     raw: RawBuf,
     offset: usize,
@@ -37,17 +120,25 @@ impl io::Write for MutBuf {
 
 impl MutBuf {
 
-    pub fn new() -> MutBuf {
-        MutBuf::with_capacity(BUF_SIZE)
+    pub fn with_capacity(cap: usize) -> MutBuf {
+        MutBuf::with_capacity_in(cap, None)
     }
 
-    pub fn with_capacity(cap: usize) -> MutBuf {
+    /// Creates a new `MutBuf` with the provided capacity. If a pool is
+    /// provided, the buffer is taken from the pool, and the buffers which
+    /// replace it are taken from the same pool.
+    pub fn with_capacity_in(cap: usize, pool: Option<BufferPool>) -> MutBuf {
         MutBuf {
-            raw: RawBuf::new(cap),
+            raw: RawBuf::new(cap, pool),
             offset: 0,
         }
     }
 
+    /// Returns the pool from which the buffer was taken.
+    pub fn pool(&self) -> Option<BufferPool> {
+        self.raw.storage.pool.clone()
+    }
+
     pub fn buf(&self, offset: usize, len: usize) -> Buf {
         unsafe {
             assert!(offset + len <= self.offset);
@@ -59,59 +150,48 @@ impl MutBuf {
         }
     }
 
-    /// Attempts to fill the buffer with at least `amount` bytes from `read`.
-    /// The remaining capacity of the buffer must exceed `amount`.
-    fn fill<R>(&mut self, read: &mut R, amount: usize) -> io::Result<()> where R: io::Read {
+    /// Returns the unfilled capacity of the buffer.
+    pub fn spare_mut(&mut self) -> &mut [u8] {
         unsafe {
-            let remaining_capacity = self.raw.len() - self.offset;
-            assert!(remaining_capacity >= amount);
-            let mut buf = slice::from_raw_parts_mut(self.raw.buf().offset(self.offset as isize),
-                                                    remaining_capacity);
-            let target_offset = self.offset + amount;
-            while self.offset < target_offset {
-                match try!(read.read(&mut buf)) {
-                    0 => return Result::Err(io::Error::new(io::ErrorKind::UnexpectedEof,
-                                                           "failed to fill whole buffer")),
-                    n => {
-                        self.offset += n;
-                        let tmp = buf;
-                        buf = &mut tmp[n..];
-                    },
-                }
-            }
+            slice::from_raw_parts_mut(self.raw.buf().offset(self.offset as isize),
+                                      self.raw.len() - self.offset)
         }
-        Ok(())
     }
 
-    /// Attemps to fill the buffer with at least `amount` bytes after the offset
-    /// `from`.
+    /// Marks the first `n` bytes of the unfilled capacity as filled.
+    pub fn commit(&mut self, n: usize) {
+        assert!(self.offset + n <= self.raw.len());
+        self.offset += n;
+    }
+
+    /// Reads from `read` into the unfilled capacity of the buffer with a
+    /// single call to `read`, and returns the number of bytes read.
+    pub fn read_from<R>(&mut self, read: &mut R) -> io::Result<usize> where R: io::Read {
+        let n = try!(read.read(self.spare_mut()));
+        self.commit(n);
+        Ok(n)
+    }
+
+    /// Ensures that the buffer has capacity for at least `amount` bytes after
+    /// the offset `from`.
     ///
     /// If the buffer does not have enough capacity it is replaced with a new
-    /// one, and `from` is reset to the corresponding offset in the new buffer.
-    pub fn fill_or_replace<R>(&mut self,
-                              read: &mut R,
-                              from: &mut usize,
-                              amount: usize)
-                              -> io::Result<()>
-    where R: io::Read {
+    /// one of at least `capacity` bytes, and `from` is reset to the
+    /// corresponding offset in the new buffer.
+    pub fn reserve(&mut self, from: &mut usize, amount: usize, capacity: usize) {
         assert!(*from <= self.offset);
-        let buffered_amount = self.offset - *from;
-        if buffered_amount >= amount {
-            return Ok(());
-        }
-        let remaining_amount = amount - buffered_amount;
-
-        if remaining_amount > self.raw.len() - self.offset {
+        if *from + amount > self.raw.len() {
 
             // Replace self with a new buffer with sufficient capacity. Copy
             // over all bytes between `from` and the current write offset, and
             // reset `from` to 0.
-            let old_buf = mem::replace(self, MutBuf::with_capacity(cmp::max(BUF_SIZE, amount + 8)));
-            try!(self.write(&old_buf[*from..]));
+            let buffered_amount = self.offset - *from;
+            let capacity = cmp::max(capacity, cmp::max(amount, buffered_amount));
+            let old_buf = mem::replace(self, MutBuf::with_capacity_in(capacity, self.pool()));
+            self.spare_mut()[..buffered_amount].copy_from_slice(&old_buf[*from..]);
+            self.commit(buffered_amount);
             *from = 0;
         }
-
-        self.fill(read, remaining_amount)
     }
 }
 
@@ -130,8 +210,9 @@ impl ops::Deref for MutBuf {
 /// A `Buf` increments the reference count of the `MutBuf`, so that a `Buf` can
 /// outlive the `MutBuf` from which it was created.
 ///
-/// The reference counting mechanism of `MutBuf` is not threadsafe, so `Buf`
-/// instances may not be shared or sent across thread boundaries.
+/// The reference counting mechanism of `MutBuf` is not threadsafe unless the
+/// `sync` feature is enabled, so `Buf` instances may not be shared or sent
+/// across thread boundaries.
 pub struct Buf {
     raw: RawBuf,
     ptr: *const u8,
@@ -158,108 +239,262 @@ impl Clone for Buf {
     }
 }
 
-/// A reference counted byte buffer.
-///
-/// The reference count is the first 8 bytes of the buffer.
-/// The buffer is not initialized.
+/// A reference counted, word-aligned byte buffer.
 ///
-/// It is left to the user to ensure that data races do not occur and
-/// unitialized data is not read.
+/// A new buffer is zero initialized, but a buffer reused from a `BufferPool`
+/// holds the bytes of its previous use. It is left to the user to ensure that
+/// data races do not occur when writing to the buffer.
 ///
-/// `RawBuf` is not threadsafe, and may not be sent or shared across thread
-/// boundaries.
+/// `RawBuf` is not threadsafe unless the `sync` feature is enabled, and may not
+/// be sent or shared across thread boundaries.
+#[derive(Clone)]
 struct RawBuf {
-    bytes: *mut u8,
-    len: usize,
+    storage: Shared<Storage>,
 }
 
 impl RawBuf {
-    /// Creates a new `RawBuf` instance with approximately the provided
-    /// length.
-    fn new(len: usize) -> RawBuf {
-        unsafe {
-            let refcount_len = mem::size_of::<u64>();
-            let len = cmp::max(refcount_len, len);
-            // The buffer is aligned to a u64. This is necessary for storing the
-            // refcount, as well as required by Cap'n Proto. This requirement is
-            // the primary reason that the raw allocation APIs are used instead
-            // of something like RawVec.
-            let bytes = match Heap.alloc(Layout::from_size_align(len, refcount_len).unwrap()) {
-                Result::Ok(val) => val,
-                Result::Err(err) => Heap.oom(err),
-            };
-            *(bytes as *mut u64) = 1;
-            RawBuf {
-                bytes: bytes.offset(refcount_len as isize),
-                len: len - refcount_len,
-            }
-        }
+    /// Creates a new `RawBuf` instance with at least the provided length,
+    /// rounded up to a multiple of the word size.
+    fn new(len: usize, pool: Option<BufferPool>) -> RawBuf {
+        RawBuf { storage: Shared::new(Storage::new((len + 7) / 8, pool)) }
     }
 
     fn buf(&self) -> *mut u8 {
-        self.bytes
+        self.storage.words as *mut u8
     }
 
     fn len(&self) -> usize {
-        self.len
+        self.storage.len * 8
     }
 }
 
-impl Clone for RawBuf {
-    fn clone(&self) -> RawBuf {
-        unsafe {
-            *(self.bytes.offset(-(mem::size_of::<u64>() as isize)) as *mut u64) += 1;
-            RawBuf {
-                bytes: self.bytes,
-                len: self.len,
-            }
+/// The storage of a `RawBuf`.
+///
+/// The storage is allocated as words, since Cap'n Proto requires that segments
+/// are word-aligned. The words are held by raw pointer, so that a `MutBuf` may
+/// write to the storage while `Buf`s hold shared references to it.
+///
+/// If the storage was taken from a pool, it is returned to the pool when
+/// dropped.
+struct Storage {
+    words: *mut u64,
+    len: usize,
+    pool: Option<BufferPool>,
+}
+
+impl Storage {
+    fn new(len: usize, pool: Option<BufferPool>) -> Storage {
+        let words: Box<[u64]> = match pool {
+            Some(ref pool) => pool.take(len),
+            None => vec![0; len].into_boxed_slice(),
+        };
+        Storage {
+            len: words.len(),
+            words: Box::into_raw(words) as *mut u64,
+            pool: pool,
         }
     }
 }
 
-impl Drop for RawBuf {
+impl Drop for Storage {
     fn drop(&mut self) {
-        unsafe {
-            let refcount_len = mem::size_of::<u64>();
-            let allocation = self.bytes.offset(-(refcount_len as isize));
-            let refcount = allocation as *mut u64;
-            *refcount -= 1;
-            if *refcount == 0 {
-                Heap.dealloc(allocation,  Layout::from_size_align(self.len + refcount_len, refcount_len).unwrap());
-            }
+        let words = unsafe {
+            Box::from_raw(ptr::slice_from_raw_parts_mut(self.words, self.len))
+        };
+        if let Some(ref pool) = self.pool {
+            pool.give(words);
         }
     }
 }
 
+/// A pool of read buffers.
+///
+/// Buffers taken from the pool are returned to it once the stream and all
+/// messages which were loaned memory from the buffer have been dropped, and are
+/// reused by later reads. A pool may be shared among many streams by cloning
+/// it.
+///
+/// The pool is not threadsafe unless the `sync` feature is enabled, so it may
+/// otherwise only be shared among streams on a single thread.
+#[derive(Clone)]
+pub struct BufferPool {
+    inner: Shared<Lock<Pool>>,
+}
+
+struct Pool {
+    /// Free buffers.
+    free: Vec<Box<[u64]>>,
+    /// The maximum number of free buffers retained by the pool.
+    max_free: usize,
+    /// The number of buffers which have been taken from the pool, and not yet
+    /// returned.
+    in_use: usize,
+}
+
+/// Statistics about the buffers of a `BufferPool`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BufferPoolStats {
+    /// The number of buffers which are in use by streams or messages.
+    pub in_use: usize,
+    /// The number of free buffers retained by the pool.
+    pub free: usize,
+}
+
+impl BufferPool {
+
+    /// Creates a new pool, which retains at most `max_free` free buffers.
+    pub fn new(max_free: usize) -> BufferPool {
+        BufferPool {
+            inner: Shared::new(Lock::new(Pool { free: Vec::new(), max_free: max_free, in_use: 0 })),
+        }
+    }
+
+    /// Returns statistics about the buffers of the pool.
+    pub fn stats(&self) -> BufferPoolStats {
+        let pool = lock(&self.inner);
+        BufferPoolStats { in_use: pool.in_use, free: pool.free.len() }
+    }
+
+    /// Takes the smallest free buffer of at least `len` words from the pool,
+    /// or allocates a new buffer if there is none.
+    fn take(&self, len: usize) -> Box<[u64]> {
+        let reused = {
+            let mut pool = lock(&self.inner);
+            pool.in_use += 1;
+            let index = pool.free.iter()
+                                 .enumerate()
+                                 .filter(|&(_, words)| words.len() >= len)
+                                 .min_by_key(|&(_, words)| words.len())
+                                 .map(|(index, _)| index);
+            index.map(|index| pool.free.swap_remove(index))
+        };
+        reused.unwrap_or_else(|| vec![0; len].into_boxed_slice())
+    }
+
+    /// Returns a buffer to the pool.
+    fn give(&self, words: Box<[u64]>) {
+        let mut pool = lock(&self.inner);
+        pool.in_use -= 1;
+        if pool.free.len() < pool.max_free {
+            pool.free.push(words);
+        }
+    }
+}
+
+impl fmt::Debug for BufferPool {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "BufferPool {{ stats: {:?} }}", self.stats())
+    }
+}
+
+/// The lock type of `BufferPool`. The lock is a mutex if the `sync` feature is
+/// enabled.
+#[cfg(not(feature = "sync"))]
+type Lock<T> = RefCell<T>;
+#[cfg(feature = "sync")]
+type Lock<T> = Mutex<T>;
+
+#[cfg(not(feature = "sync"))]
+fn lock<'a, T>(lock: &'a Lock<T>) -> RefMut<'a, T> {
+    lock.borrow_mut()
+}
+
+#[cfg(feature = "sync")]
+fn lock<'a, T>(lock: &'a Lock<T>) -> MutexGuard<'a, T> {
+    lock.lock().unwrap()
+}
+
+/// The shared pointer type of `RawBuf` storage. The reference count is atomic
+/// if the `sync` feature is enabled.
+#[cfg(not(feature = "sync"))]
+type Shared<T> = Rc<T>;
+#[cfg(feature = "sync")]
+type Shared<T> = Arc<T>;
+
+#[cfg(feature = "sync")]
+unsafe impl Send for Storage {}
+#[cfg(feature = "sync")]
+unsafe impl Sync for Storage {}
+#[cfg(feature = "sync")]
+unsafe impl Send for Buf {}
+#[cfg(feature = "sync")]
+unsafe impl Sync for Buf {}
+
 #[cfg(test)]
 mod test {
 
     use std::io::{Cursor, Write};
 
-    use super::{MutBuf, RawBuf};
+    use super::{BufferOptions, BufferPool, BufferPoolStats, MutBuf, RawBuf};
 
     use quickcheck::{quickcheck, TestResult};
 
     #[test]
     fn test_create_raw_buf() {
-        let raw = RawBuf::new(128 * 1024);
-        assert_eq!(128 * 1024 - 8, raw.len());
+        let raw = RawBuf::new(128 * 1024, None);
+        assert_eq!(128 * 1024, raw.len());
+        assert_eq!(16, RawBuf::new(9, None).len());
     }
 
     #[test]
     fn raw_buf_is_cloneable() {
-        let raw = RawBuf::new(0);
+        let raw = RawBuf::new(0, None);
         let clone = raw.clone();
         assert_eq!(0, clone.len());
     }
 
     #[test]
     fn mut_buf_write() {
-        let mut buf = MutBuf::with_capacity(16);
+        let mut buf = MutBuf::with_capacity(8);
         assert_eq!(8, buf.write(b"abcdefghijk").unwrap());
         assert_eq!(0, buf.write(b"abcdefghijk").unwrap());
     }
 
+    #[test]
+    fn replacement_size() {
+        let mut options = BufferOptions::new();
+        options.min_size(64).max_size(1024);
+        assert_eq!(64, options.replacement_size(8, 32));
+        assert_eq!(100, options.replacement_size(100, 32));
+        assert_eq!(2048, options.replacement_size(2048, 32));
+
+        options.adaptive(true);
+        assert_eq!(64, options.replacement_size(8, 0));
+        assert_eq!(512, options.replacement_size(8, 32));
+        assert_eq!(1024, options.replacement_size(8, 1024));
+        assert_eq!(2048, options.replacement_size(2048, 1024));
+    }
+
+    #[test]
+    fn buffer_pool() {
+        let pool = BufferPool::new(1);
+        assert_eq!(BufferPoolStats { in_use: 0, free: 0 }, pool.stats());
+
+        let mut buf = MutBuf::with_capacity_in(64, Some(pool.clone()));
+        let ptr = buf.raw.buf();
+        buf.write_all(b"abcdefgh").unwrap();
+        let shared = buf.buf(0, 8);
+        assert_eq!(BufferPoolStats { in_use: 1, free: 0 }, pool.stats());
+
+        // The buffer is returned to the pool when the last reference is dropped.
+        drop(buf);
+        assert_eq!(BufferPoolStats { in_use: 1, free: 0 }, pool.stats());
+        drop(shared);
+        assert_eq!(BufferPoolStats { in_use: 0, free: 1 }, pool.stats());
+
+        // Free buffers are reused if they are large enough.
+        let buf = MutBuf::with_capacity_in(128, Some(pool.clone()));
+        assert_eq!(BufferPoolStats { in_use: 1, free: 1 }, pool.stats());
+        let reused = MutBuf::with_capacity_in(32, Some(pool.clone()));
+        assert_eq!(ptr, reused.raw.buf());
+        assert_eq!(BufferPoolStats { in_use: 2, free: 0 }, pool.stats());
+
+        // At most `max_free` buffers are retained.
+        drop(buf);
+        drop(reused);
+        assert_eq!(BufferPoolStats { in_use: 0, free: 1 }, pool.stats());
+    }
+
     #[test]
     fn buf() {
         let mut buf = MutBuf::with_capacity(16);
@@ -276,11 +511,15 @@ mod test {
     }
 
     #[test]
-    fn fill_or_replace() {
+    fn reserve() {
         let mut buf = MutBuf::with_capacity(14);
         buf.write_all(b"abcdef").unwrap();
         let mut offset = 3;
-        buf.fill_or_replace(&mut Cursor::new("ghi"), &mut offset, 6).unwrap();
+        buf.reserve(&mut offset, 6, 0);
+        assert_eq!(3, offset);
+        buf.reserve(&mut offset, 16, 0);
+        assert_eq!(0, offset);
+        buf.read_from(&mut Cursor::new("ghi")).unwrap();
         assert_eq!(b"defghi", &*buf.buf(offset, 6));
     }
 
@@ -310,13 +549,13 @@ mod test {
     }
 
     #[test]
-    fn check_fill() {
-        fn fill(segments: Vec<Vec<u8>>) -> TestResult {
+    fn check_read_from() {
+        fn read_from(segments: Vec<Vec<u8>>) -> TestResult {
             let total_len: usize = segments.iter().fold(0, |acc, segment| acc + segment.len());
             let mut buf = MutBuf::with_capacity(total_len + 8);
 
             for segment in &segments {
-                buf.fill(&mut Cursor::new(segment), segment.len()).unwrap();
+                assert_eq!(segment.len(), buf.read_from(&mut Cursor::new(segment)).unwrap());
             }
 
             let mut offset = 0;
@@ -331,11 +570,11 @@ mod test {
             TestResult::passed()
         }
 
-        quickcheck(fill as fn(Vec<Vec<u8>>) -> TestResult);
+        quickcheck(read_from as fn(Vec<Vec<u8>>) -> TestResult);
     }
 
     #[test]
-    fn check_fill_or_replace() {
+    fn check_reserve() {
         fn fill(a: Vec<u8>, b: Vec<u8>, c: Vec<u8>) -> TestResult {
             let mut buf = MutBuf::with_capacity(8 + a.len() + b.len());
 
@@ -344,7 +583,8 @@ mod test {
 
             let mut offset = a.len();
 
-            buf.fill_or_replace(&mut Cursor::new(&c), &mut offset, b.len() + c.len()).unwrap();
+            buf.reserve(&mut offset, b.len() + c.len(), 0);
+            buf.read_from(&mut Cursor::new(&c)).unwrap();
 
             if &b[..] != &*buf.buf(offset, b.len()) {
                 return TestResult::failed();
@@ -359,4 +599,13 @@ mod test {
 
         quickcheck(fill as fn(Vec<u8>, Vec<u8>, Vec<u8>) -> TestResult);
     }
+
+    #[cfg(feature = "sync")]
+    #[test]
+    fn buf_is_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        fn assert_send<T: Send>() {}
+        assert_send_sync::<super::Buf>();
+        assert_send::<super::MutBuf>();
+    }
 }
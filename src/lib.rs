@@ -1,53 +1,94 @@
 //! `capnp_nonblock` provides a helper struct, `MessageStream`, for reading and
 //! writing [Cap'n Proto](https://capnproto.org/) messages to non-blocking
 //! streams.
+//!
+//! Messages may be framed with either the standard or the packed Cap'n Proto
+//! serialization format.
+//!
+//! A `MessageStream` may be split into independent `MessageReader` and
+//! `MessageWriter` halves, so that inbound and outbound messages can be
+//! processed separately.
+//!
+//! By default, messages read by `MessageStream` may not be sent or shared
+//! across threads. Enabling the `sync` cargo feature makes the reference
+//! counting of read buffers atomic, so that read `Segments` are `Send + Sync`.
+//!
+//! Enabling the `async` cargo feature provides `AsyncMessageStream`, which
+//! adapts a `MessageStream` over Tokio's `AsyncRead` and `AsyncWrite` into a
+//! `Stream` of inbound messages and a `Sink` of outbound messages.
+//!
+//! Enabling the `mio` cargo feature implements `ShutdownWrite` for Mio's
+//! `TcpStream` and `UnixStream`, so that message streams over them may be
+//! gracefully shut down.
+//!
+//! Messages read from one stream may be forwarded unchanged to another, by
+//! queueing their `Segments` as outbound messages. The segments are written
+//! directly from the buffers they were read into, without being copied.
+//!
+//! The framing protocol itself is implemented by `Decoder` and `Encoder`,
+//! which perform no IO. Received bytes are pushed into a `Decoder`, which
+//! returns complete messages, and an `Encoder` provides the bytes of queued
+//! messages which remain to be written, and is told how many were written.
+//! `MessageStream` drives a `Decoder` and `Encoder` over a non-blocking stream.
 
-#![feature(alloc, allocator_api)]
 #![doc(html_root_url = "https://docs.rs/capnp-nonblock/0.4.0")]
 
-extern crate alloc;
 extern crate byteorder;
 extern crate capnp;
 
+#[cfg(feature = "async")]
+extern crate futures_core;
+#[cfg(feature = "async")]
+extern crate futures_sink;
+#[cfg(feature = "async")]
+extern crate tokio;
+#[cfg(feature = "mio")]
+extern crate mio;
+
 #[cfg(test)]
 extern crate quickcheck;
+#[cfg(all(test, feature = "async"))]
+extern crate futures;
 
+#[cfg(feature = "async")]
+mod async_io;
 mod buf;
+mod outbound;
+mod packed;
+mod poison;
+mod read;
+mod typed;
+mod write;
 
 #[cfg(test)]
 mod test_utils;
 
-use std::borrow::Borrow;
-use std::collections::VecDeque;
 use std::fmt;
-use std::io::{self, Error, ErrorKind, Result};
-use std::marker;
-use std::mem;
+use std::io::{self, Result};
 use std::result;
 
-use byteorder::{ByteOrder, LittleEndian};
-use capnp::Word;
 use capnp::message::{
-    Allocator,
     Builder,
     HeapAllocator,
     Reader,
     ReaderOptions,
-    ReaderSegments,
 };
 
-use buf::{MutBuf, Buf};
-
-/// A Cap'n Proto message container.
-pub struct Segments {
-    segments: Vec<Buf>,
-}
-
-impl ReaderSegments for Segments {
-    fn get_segment(&self, id: u32) -> Option<&[Word]> {
-        self.segments.get(id as usize).map(|buf| Word::bytes_to_words(&*buf))
-    }
-}
+#[cfg(feature = "async")]
+pub use async_io::AsyncMessageStream;
+pub use buf::{BufferOptions, BufferPool, BufferPoolStats};
+pub use outbound::{FramedMessage, OutboundMessage};
+pub use read::{
+    Decoder,
+    LimitError,
+    MessageHeader,
+    MessageLimits,
+    Segments,
+    SkippedMessage,
+};
+pub use packed::UnpackedRemainder;
+pub use typed::{TypedBuilder, TypedMessageStream, TypedReader};
+pub use write::{Completed, Encoder, QueueOptions, ShutdownWrite, Ticket, WriteError};
 
 /// A `MessageStream` wraps a stream, and provides methods to read and write
 /// Cap'n Proto messages to the stream. `MessageStream` performs its own
@@ -55,78 +96,139 @@ impl ReaderSegments for Segments {
 ///
 /// If the underlying stream is non-blocking, `MessageStream` will automatically
 /// pause reading and writing messages, and will resume during the next call to
-/// `read_message` or `write`.
+/// `read_message` or `write`. Outbound messages are written with vectored
+/// writes, so that the segment tables and segments of many queued messages may
+/// be written with a single system call.
+///
+/// Outbound messages may be of any type implementing `OutboundMessage`, which
+/// includes message builders, the `Segments` of read messages, already framed
+/// `FramedMessage` bytes, and reference counted or boxed messages.
+///
+/// Up to version 0.4, `MessageStream` took an allocator type parameter ahead
+/// of the message type, as `MessageStream<S, A, M>` where
+/// `M: Borrow<Builder<A>>`. The allocator is now determined by the message
+/// type alone, so for instance `MessageStream<S, A, Rc<Builder<A>>>` becomes
+/// `MessageStream<S, Rc<Builder<A>>>`.
+///
+/// `MessageStream` may be created with `new_packed` to read and write messages
+/// in the packed serialization format. Packing and unpacking is performed
+/// incrementally, alongside reads and writes to the stream.
 ///
 /// `MessageStream` attempts to reduce the number of required allocations when
 /// reading messages by allocating memory in large chunks, which it loans out to
-/// messages via reference counting. The reference counting is not thread safe,
-/// so messages read by `MessageStream` may not be sent or shared across thread
-/// boundaries.
-pub struct MessageStream<S, A=HeapAllocator, M=Builder<A>> {
+/// messages via reference counting. The reference counting is not thread safe
+/// unless the `sync` feature is enabled, so messages read by `MessageStream`
+/// may not otherwise be sent or shared across thread boundaries.
+pub struct MessageStream<S, M=Builder<HeapAllocator>> {
     inner: S,
-    options: ReaderOptions,
-
-    /// The current read buffer.
-    buf: MutBuf,
-    /// The current read offset.
-    buf_offset: usize,
-    /// The segment sizes of the remaining segments of message currently being
-    /// read, in reverse order.
-    remaining_segments: Vec<usize>,
-    /// The segments of the message currently being read.
-    segments: Vec<Buf>,
-
-    /// Queue of outbound messages which have not yet begun being written to the
-    /// stream.
-    outbound_queue: VecDeque<M>,
-
-    /// The serialized segment table of the message currently being written to
-    /// the stream.
-    current_segment_table: Vec<u8>,
-
-    /// The progress of the current write. The message currently being written
-    /// is a the front of the outbound queue.
-    ///
-    /// The first corresponds to the segment currently being written, offset by
-    /// 1, or 0 if the segment table is being written. The second corresponds to
-    /// the offset within the current segment.
-    write_progress: Option<(usize, usize)>,
-
-    marker_: marker::PhantomData<A>,
+    decoder: Decoder,
+    encoder: Encoder<M>,
+    /// Whether `on_ready` stopped reading messages because the outbound queue
+    /// rose above its high watermark. The pause lapses once the queue has
+    /// drained below its low watermark.
+    read_paused: bool,
+    /// Shuts down the write half of the inner stream, once the stream has been
+    /// closed for writing by `close_write` and the outbound queue has drained.
+    shutdown_write: Option<fn(&mut S) -> io::Result<()>>,
 }
 
-impl <S, M, A> MessageStream<S, M, A> {
+impl <S, M> MessageStream<S, M> {
 
     /// Creates a new `MessageStream` instance wrapping the provided stream, and
     /// with the provided reader options.
-    pub fn new(inner: S, options: ReaderOptions) -> MessageStream<S, M, A> {
+    pub fn new(inner: S, options: ReaderOptions) -> MessageStream<S, M> {
+        MessageStream::with_packing(inner, options, false)
+    }
+
+    /// Creates a new `MessageStream` instance wrapping the provided stream, and
+    /// with the provided reader options. Messages are read and written in the
+    /// packed serialization format.
+    pub fn new_packed(inner: S, options: ReaderOptions) -> MessageStream<S, M> {
+        MessageStream::with_packing(inner, options, true)
+    }
+
+    fn with_packing(inner: S, options: ReaderOptions, packed: bool) -> MessageStream<S, M> {
         MessageStream {
             inner: inner,
-            options: options,
-            buf: MutBuf::new(),
-            buf_offset: 0,
-            remaining_segments: Vec::new(),
-            segments: Vec::new(),
-            outbound_queue: VecDeque::new(),
-            current_segment_table: Vec::new(),
-            write_progress: None,
-            marker_: marker::PhantomData,
+            decoder: if packed { Decoder::new_packed(options) } else { Decoder::new(options) },
+            encoder: if packed { Encoder::new_packed() } else { Encoder::new() },
+            read_paused: false,
+            shutdown_write: None,
         }
     }
 
     /// Returns the number of queued outbound messages.
     pub fn outbound_queue_len(&self) -> usize {
-        self.outbound_queue.len()
+        self.encoder.outbound_queue_len()
+    }
+
+    /// Returns the total length of the queued outbound messages, in bytes.
+    pub fn outbound_queue_bytes(&self) -> usize {
+        self.encoder.outbound_queue_bytes()
+    }
+
+    /// Returns `true` if the outbound queue is full, so that `write_message`
+    /// would hand the message back.
+    pub fn is_outbound_queue_full(&self) -> bool {
+        self.encoder.is_outbound_queue_full()
+    }
+
+    /// Returns `true` if the outbound queue is at or above its high watermark,
+    /// in which case the caller should stop producing outbound messages.
+    pub fn is_above_high_watermark(&self) -> bool {
+        self.encoder.is_above_high_watermark()
+    }
+
+    /// Returns `true` if the outbound queue is at or below its low watermark,
+    /// in which case the caller may resume producing outbound messages.
+    pub fn is_below_low_watermark(&self) -> bool {
+        self.encoder.is_below_low_watermark()
     }
 
     /// Clears the outbound message queue of all messages that have not begun
-    /// writing yet.
+    /// writing yet. The cleared messages are not completed.
     pub fn clear_outbound_queue(&mut self) {
-        if self.write_progress.is_some() {
-            self.outbound_queue.drain(1..);
-        } else {
-            self.outbound_queue.clear();
-        }
+        self.encoder.clear_outbound_queue()
+    }
+
+    /// Returns the completely written outbound messages which have been
+    /// retained since the previous call, so that they may be reused. Messages
+    /// are only retained if `QueueOptions::retain_written` is set.
+    pub fn take_written_messages(&mut self) -> Vec<M> {
+        self.encoder.take_written_messages()
+    }
+
+    /// Removes the queued outbound message with the provided ticket, and
+    /// returns it. Returns `None` if the message has begun writing, or is no
+    /// longer queued. A cancelled message is not completed.
+    pub fn cancel_message(&mut self, ticket: Ticket) -> Option<M> {
+        self.encoder.cancel_message(ticket)
+    }
+
+    /// Removes the queued outbound messages which have not begun writing and
+    /// for which the predicate returns `true`, and returns them in queue order.
+    /// Cancelled messages are not completed.
+    pub fn cancel_messages<F>(&mut self, predicate: F) -> Vec<M>
+    where F: FnMut(Ticket, &M) -> bool {
+        self.encoder.cancel_messages(predicate)
+    }
+
+    /// Returns `true` if messages are read and written in the packed
+    /// serialization format.
+    pub fn is_packed(&self) -> bool {
+        self.decoder.is_packed()
+    }
+
+    /// Returns `true` if the stream has been closed at a message boundary, and
+    /// all inbound messages have been read.
+    pub fn is_closed(&self) -> bool {
+        self.decoder.is_closed()
+    }
+
+    /// Returns `true` if a previous read error has poisoned the stream, so
+    /// that `read_message` fails.
+    pub fn is_read_poisoned(&self) -> bool {
+        self.decoder.is_poisoned()
     }
 
     /// Returns the inner stream.
@@ -138,275 +240,691 @@ impl <S, M, A> MessageStream<S, M, A> {
     pub fn inner(&self) -> &S {
         &self.inner
     }
-}
 
-impl <S, M, A> MessageStream<S, M, A> where S: io::Read {
+    /// Sets the options which control the sizes of the buffers allocated to
+    /// hold inbound messages. This should be called before any messages are
+    /// read, so that the initial buffer has the configured size.
+    pub fn with_buffer_options(mut self, options: BufferOptions) -> MessageStream<S, M> {
+        self.decoder.set_buffer_options(options);
+        self
+    }
 
-    /// Reads the segment table, populating the `remaining_segments` field of the
-    /// reader on success.
-    fn read_segment_table(&mut self) -> io::Result<()> {
-        let MessageStream {
-            ref mut inner,
-            ref options,
-            ref mut buf,
-            ref mut buf_offset,
-            ref mut remaining_segments,
-            ..
-        } = *self;
+    /// Sets the pool from which the buffers which hold inbound messages are
+    /// taken. This should be called before any messages are read.
+    pub fn with_buffer_pool(mut self, pool: BufferPool) -> MessageStream<S, M> {
+        self.decoder.set_buffer_pool(pool);
+        self
+    }
 
-        loop {
-            assert!(remaining_segments.is_empty());
-            match parse_segment_table(&buf[*buf_offset..], remaining_segments) {
-                Ok(0) => break,
-                Ok(n) => try!(buf.fill_or_replace(inner, buf_offset, n)),
-                Err(error) => return Err(error),
-            }
-        }
+    /// Sets the limits on the framing of inbound messages. Messages which
+    /// violate the limits fail to read with an error wrapping a `LimitError`.
+    pub fn with_message_limits(mut self, limits: MessageLimits) -> MessageStream<S, M> {
+        self.decoder.set_message_limits(limits);
+        self
+    }
 
-        *buf_offset += (remaining_segments.len() / 2 + 1) * 8;
+    /// Seeds the stream with bytes which have already been read from the inner
+    /// stream, for instance by a handshake which read past its end. The bytes
+    /// are in the serialization format of the stream, and are parsed by
+    /// `read_message` before the inner stream is read. This should be called
+    /// after the buffer options and pool are set.
+    pub fn with_prefix(mut self, bytes: &[u8]) -> MessageStream<S, M> {
+        self.decoder.push(bytes);
+        self
+    }
 
-        let total_len = remaining_segments.iter()
-                                          .fold(Some(0u64), |acc, &len| {
-                                              acc.and_then(|n| n.checked_add(len as u64))
-                                          });
-        match total_len {
-            Some(len) if len <= options.traversal_limit_in_words * 8 => (),
-            _ => return Err(io::Error::new(io::ErrorKind::InvalidData,
-                                           "Cap'n Proto message is too large".to_string())),
-        }
+    /// Returns `true` if a previous write error has poisoned the stream, so
+    /// that `write` and `write_message` fail.
+    pub fn is_write_poisoned(&self) -> bool {
+        self.encoder.is_poisoned()
+    }
 
-        remaining_segments.reverse();
-        Ok(())
+    /// Returns `true` if the stream has been closed for writing.
+    pub fn is_write_closed(&self) -> bool {
+        self.encoder.is_closed()
     }
 
-    /// Reads a message segment from the stream.
-    fn read_segment(&mut self, len: usize) -> Result<Buf> {
-        let MessageStream {
-            ref mut inner,
-            ref mut buf,
-            ref mut buf_offset,
-            ..
-        } = *self;
-        try!(buf.fill_or_replace(inner, buf_offset, len));
-        let buf = buf.buf(*buf_offset, len);
-        *buf_offset += len;
-        Ok(buf)
+    /// Returns `true` if the write half of the inner stream has been shut down.
+    pub fn is_shut_down(&self) -> bool {
+        self.encoder.is_shut_down()
     }
 
-    /// Reads a message from the stream.
-    fn read(&mut self) -> io::Result<Reader<Segments>> {
-        if self.remaining_segments.is_empty() {
-            try!(self.read_segment_table());
-        }
+    /// Returns `true` if the stream should be polled for readability. This is
+    /// the case until the stream is closed or poisoned, except after `on_ready`
+    /// has stopped reading because the outbound queue rose above its high
+    /// watermark, until the queue has drained below its low watermark.
+    pub fn wants_read(&self) -> bool {
+        !self.is_closed() && !self.is_read_poisoned() && !self.is_read_paused()
+    }
 
-        while let Some(&segment_len) = self.remaining_segments.last() {
-            let segment = try!(self.read_segment(segment_len));
-            self.segments.push(segment);
-            // Only pop the segment length once we know there hasn't been an error.
-            self.remaining_segments.pop();
-        }
+    /// Returns `true` if reading is paused until the outbound queue drains
+    /// below its low watermark.
+    fn is_read_paused(&self) -> bool {
+        self.read_paused && !self.is_below_low_watermark()
+    }
+
+    /// Returns `true` if the stream should be polled for writability, because
+    /// outbound messages are queued, or the stream has been closed for writing
+    /// and its write half remains to be shut down.
+    pub fn wants_write(&self) -> bool {
+        !self.is_write_poisoned()
+            && (self.outbound_queue_len() > 0 || (self.shutdown_write.is_some() && !self.is_shut_down()))
+    }
+
+    /// Returns the readiness which the stream should be polled for.
+    pub fn interest(&self) -> Interest {
+        Interest { readable: self.wants_read(), writable: self.wants_write() }
+    }
 
+    /// Sets the options which limit the outbound message queue.
+    pub fn with_queue_options(mut self, options: QueueOptions) -> MessageStream<S, M> {
+        self.encoder.set_queue_options(options);
+        self
+    }
 
-        Ok(Reader::new(Segments { segments: mem::replace(&mut self.segments, Vec::new()) },
-                       self.options.clone()))
+    /// Splits the stream into independent reader and writer halves.
+    ///
+    /// The reader half takes ownership of the inner stream, and the writer half
+    /// writes to `write`, which is typically a clone of the inner stream (for
+    /// instance from `TcpStream::try_clone`). Partially read messages, queued
+    /// outbound messages, and the progress of the current write are retained by
+    /// the respective halves. A shutdown pending from `close_write` is not
+    /// retained, since `write` differs in type; call `close_write` on the writer
+    /// half to shut it down.
+    pub fn split<W>(self, write: W) -> (MessageReader<S>, MessageWriter<W, M>) {
+        let MessageStream { inner, decoder, encoder, .. } = self;
+        (MessageReader { inner: inner, decoder: decoder },
+         MessageWriter { inner: write, encoder: encoder, shutdown_write: None })
     }
+}
+
+impl <S, M> MessageStream<S, M> where S: io::Read {
 
     /// Returns the next message from the stream, or `None` if the entire
-    /// message is not yet available.
+    /// message is not yet available, or if the stream has been closed.
     ///
-    /// If an `Err` result is returned, then the stream must be considered
-    /// corrupt, and `read_message` must not be called again.
+    /// If the stream is closed at a message boundary, `None` is returned and
+    /// `is_closed` returns `true`. If the stream is closed in the middle of a
+    /// message, an `UnexpectedEof` error is returned.
+    ///
+    /// If an `Err` result is returned, then the stream is corrupt, and every
+    /// further call to `read_message` fails; see `is_read_poisoned`. The
+    /// exception is an error which reports a `SkippedMessage`, after which the
+    /// next message may be read.
     pub fn read_message(&mut self) -> Result<Option<Reader<Segments>>> {
-        match self.read() {
-            Err(ref error) if error.kind() == io::ErrorKind::WouldBlock => Ok(None),
-            Err(error) => Err(From::from(error)),
-            Ok(message) => Ok(Some(message)),
+        self.decoder.read_message(&mut self.inner)
+    }
+
+    /// Returns the segment table of the next message from the stream, once it
+    /// has been read, without reading the rest of the message. Returns `None`
+    /// if the segment table is not yet available, or if the stream has been
+    /// closed.
+    ///
+    /// The message itself is returned by a following call to `read_message`.
+    /// The message limits are checked when the segment table is read, so
+    /// errors are returned as by `read_message`.
+    pub fn peek_message_header(&mut self) -> Result<Option<MessageHeader>> {
+        self.decoder.peek_message_header(&mut self.inner)
+    }
+}
+
+impl <S, M> MessageStream<S, M> where S: io::Write, M: OutboundMessage {
+
+    /// Writes queued messages to the stream. This should be called when the
+    /// stream is in non-blocking mode and writable.
+    ///
+    /// Returns the tickets of the messages which have been completely written
+    /// since the previous call to `write`, including those written by
+    /// `write_message`.
+    ///
+    /// If the stream has been closed with `close_write`, the write half of the
+    /// inner stream is shut down once every queued message has been written.
+    ///
+    /// If an `Err` result is returned, then the stream is corrupt, and every
+    /// further call to `write` or `write_message` fails; see
+    /// `is_write_poisoned`.
+    pub fn write(&mut self) -> io::Result<Completed> {
+        let completed = try!(self.encoder.write(&mut self.inner));
+        if let Some(shutdown_write) = self.shutdown_write {
+            try!(self.encoder.shutdown_with(&mut self.inner, shutdown_write));
+        }
+        Ok(completed)
+    }
+
+    /// Queue message for write.
+    ///
+    /// This method optimistically begins writing to the stream if there is no
+    /// message currently being written. This is necessary for the blocking
+    /// stream case, and efficient in the non-blocking case as well, since it is
+    /// likely that the stream is writable.
+    ///
+    /// Returns the ticket of the message, which is reported by `write` once the
+    /// message has been completely written.
+    ///
+    /// If the outbound queue is full, the message is not queued, and is handed
+    /// back with `WriteError::QueueFull`. If the stream has been closed for
+    /// writing, the message is handed back with `WriteError::Closed`, and if
+    /// the stream has been poisoned by a previous error, the message is handed
+    /// back with `WriteError::Poisoned`. Otherwise, if an `Err` result is
+    /// returned, then the stream is corrupt, and every further call to `write`
+    /// or `write_message` fails; see `is_write_poisoned`.
+    pub fn write_message(&mut self, message: M) -> result::Result<Ticket, WriteError<M>> {
+        self.encoder.write_message(&mut self.inner, message)
+    }
+}
+
+impl <S, M> MessageStream<S, M> where S: io::Write + ShutdownWrite, M: OutboundMessage {
+
+    /// Closes the stream for writing, so that `write_message` hands back
+    /// further messages with `WriteError::Closed`. Messages which have already
+    /// been queued are still written by `write`, and once the outbound queue
+    /// has drained, `write` shuts down the write half of the inner stream.
+    pub fn close_write(&mut self) {
+        self.encoder.close();
+        self.shutdown_write = Some(S::shutdown_write);
+    }
+
+    /// Gracefully shuts down the write half of the stream. The stream is closed
+    /// for writing, queued messages are written, and once the outbound queue
+    /// has drained, the write half of the inner stream is shut down.
+    ///
+    /// Returns `true` once the write half has been shut down. Otherwise, queued
+    /// messages remain, and either `shutdown` or `write` should be called again
+    /// when the stream is writable.
+    pub fn shutdown(&mut self) -> io::Result<bool> {
+        self.close_write();
+        self.encoder.shutdown(&mut self.inner)
+    }
+}
+
+impl <S, M> MessageStream<S, M> where S: io::Read + io::Write, M: OutboundMessage {
+
+    /// Drives the stream after it has been reported ready by an event loop, and
+    /// returns the readiness which the stream should be polled for next, along
+    /// with the tickets of the messages which have been completely written.
+    ///
+    /// If the stream is writable, queued messages are written. If the stream
+    /// is readable, messages are read and passed to `on_message` until the
+    /// stream would block, so that no readiness is missed by edge-triggered
+    /// event loops. `on_message` may write responses to the stream, and the
+    /// tickets of those which are completely written are returned as well.
+    ///
+    /// Reading stops while the outbound queue is above its high watermark, so
+    /// that a peer which does not read its responses cannot make the stream
+    /// buffer without bound. Reading resumes once writes have drained the queue
+    /// below its low watermark, without waiting for the stream to be reported
+    /// readable again.
+    ///
+    /// Messages which are skipped because they exceed the message limits are
+    /// passed over, and reading continues with the next message. Other errors
+    /// returned by reading, writing, or `on_message` are passed through.
+    pub fn on_ready<F>(&mut self, readable: bool, writable: bool, mut on_message: F)
+                       -> Result<(Interest, Completed)>
+    where F: FnMut(&mut MessageStream<S, M>, Reader<Segments>) -> Result<()> {
+        let mut completed = if writable { try!(self.write()) } else { Completed::default() };
+
+        if readable || (self.read_paused && !self.is_read_paused()) {
+            self.read_paused = false;
+            while !self.is_closed() {
+                if self.is_above_high_watermark() {
+                    self.read_paused = true;
+                    break;
+                }
+                match self.read_message() {
+                    Ok(Some(message)) => try!(on_message(self, message)),
+                    Ok(None) => break,
+                    Err(ref error) if error.get_ref().is_some_and(|error| error.is::<SkippedMessage>()) => (),
+                    Err(error) => return Err(error),
+                }
+            }
+        }
+
+        // Responses written by `on_message` may have been completed as well.
+        completed.extend(self.encoder.take_completed());
+        Ok((self.interest(), completed))
+    }
+}
+
+/// The readiness which a message stream should be polled for by an event loop.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Interest {
+    /// Whether the stream should be polled for readability.
+    pub readable: bool,
+
+    /// Whether the stream should be polled for writability.
+    pub writable: bool,
+}
+
+impl <S, M> MessageStream<S, M> where M: OutboundMessage {
+
+    /// Unwraps the stream, and returns it along with the inbound bytes which
+    /// have been read from it but not returned as messages, and the outbound
+    /// messages which have not been completely written to it. This allows the
+    /// stream to be handed off to another protocol handler, or another thread.
+    pub fn into_parts(self) -> Parts<S, M> {
+        let MessageStream { inner, decoder, encoder, .. } = self;
+        let (outbound, outbound_written) = encoder.into_queued();
+        Parts {
+            inner: inner,
+            inbound: decoder.into_buffered(),
+            outbound: outbound,
+            outbound_written: outbound_written,
         }
     }
 }
 
-impl <S, A, M> fmt::Debug for MessageStream<S, A, M> where S: fmt::Debug {
+/// The parts of a `MessageStream`, returned by `into_parts`.
+pub struct Parts<S, M> {
+    /// The inner stream.
+    pub inner: S,
+
+    /// The bytes which have been read from the stream, but not returned as
+    /// messages, beginning with the partially read message, if any. The bytes
+    /// are in the serialization format of the stream, so the rest of the stream
+    /// follows them.
+    ///
+    /// This is an error if the stream has been poisoned by a previous read
+    /// error, or if the stream is in the middle of a skipped message. If the
+    /// packed stream is in the middle of a run of verbatim words, and the bytes
+    /// can not be packed again, the error wraps an `UnpackedRemainder` which
+    /// holds them instead.
+    pub inbound: Result<Vec<u8>>,
+
+    /// The outbound messages which have not been completely written, in queue
+    /// order.
+    pub outbound: Vec<M>,
+
+    /// The number of bytes of the first outbound message which have already
+    /// been written to the stream, in the serialization format of the stream.
+    /// For packed streams, this only matches the packing of an `Encoder`; see
+    /// `Encoder::into_queued`.
+    pub outbound_written: usize,
+}
+
+impl <S, M> fmt::Debug for Parts<S, M> where S: fmt::Debug {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Parts {{ inner: {:?}, inbound: {:?}, outbound_messages: {}, outbound_written: {} }}",
+               self.inner, self.inbound, self.outbound.len(), self.outbound_written)
+    }
+}
+
+impl <S, M> fmt::Debug for MessageStream<S, M> where S: fmt::Debug {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "MessageStream {{ inner: {:?}, outbound_messages: {} }}",
                self.inner, self.outbound_queue_len())
     }
 }
 
-/// Serializes the segment table for the provided segments.
-fn serialize_segment_table(segment_table: &mut Vec<u8>, segments: &[&[Word]]) {
-    segment_table.clear();
+/// The read half of a `MessageStream`.
+///
+/// A `MessageReader` may be created directly, or by splitting a
+/// `MessageStream`. The messages read by `MessageReader` may not be sent or
+/// shared across thread boundaries unless the `sync` feature is enabled.
+pub struct MessageReader<S> {
+    inner: S,
+    decoder: Decoder,
+}
 
-    let mut buf: [u8; 4] = [0; 4];
+impl <S> MessageReader<S> {
 
-    <LittleEndian as ByteOrder>::write_u32(&mut buf[..], segments.len() as u32 - 1);
-    segment_table.extend(&buf);
+    /// Creates a new `MessageReader` instance wrapping the provided stream, and
+    /// with the provided reader options.
+    pub fn new(inner: S, options: ReaderOptions) -> MessageReader<S> {
+        MessageReader { inner: inner, decoder: Decoder::new(options) }
+    }
 
-    for segment in segments {
-        <LittleEndian as ByteOrder>::write_u32(&mut buf[..], segment.len() as u32);
-        segment_table.extend(&buf);
+    /// Creates a new `MessageReader` instance wrapping the provided stream, and
+    /// with the provided reader options. Messages are read in the packed
+    /// serialization format.
+    pub fn new_packed(inner: S, options: ReaderOptions) -> MessageReader<S> {
+        MessageReader { inner: inner, decoder: Decoder::new_packed(options) }
     }
 
-    if segments.len() % 2 == 0 {
-        segment_table.extend(&[0, 0, 0, 0]);
+    /// Sets the options which control the sizes of the buffers allocated to
+    /// hold inbound messages. This should be called before any messages are
+    /// read, so that the initial buffer has the configured size.
+    pub fn with_buffer_options(mut self, options: BufferOptions) -> MessageReader<S> {
+        self.decoder.set_buffer_options(options);
+        self
     }
-}
 
-/// Like Write::write_all, but increments `offset` after every successful
-/// write.
-fn write_segment<W>(write: &mut W, mut buf: &[u8], offset: &mut usize) -> io::Result<()>
-where W: io::Write {
-    while !buf.is_empty() {
-        match write.write(buf) {
-            Ok(0) => return result::Result::Err(io::Error::new(io::ErrorKind::WriteZero,
-                                                                "failed to write whole message")),
-            Ok(n) => { *offset += n; buf = &buf[n..] },
-            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
-            Err(e) => return Err(e),
-        }
+    /// Sets the pool from which the buffers which hold inbound messages are
+    /// taken. This should be called before any messages are read.
+    pub fn with_buffer_pool(mut self, pool: BufferPool) -> MessageReader<S> {
+        self.decoder.set_buffer_pool(pool);
+        self
+    }
+
+    /// Sets the limits on the framing of inbound messages. Messages which
+    /// violate the limits fail to read with an error wrapping a `LimitError`.
+    pub fn with_message_limits(mut self, limits: MessageLimits) -> MessageReader<S> {
+        self.decoder.set_message_limits(limits);
+        self
+    }
+
+    /// Seeds the stream with bytes which have already been read from the inner
+    /// stream, for instance by a handshake which read past its end. The bytes
+    /// are in the serialization format of the stream, and are parsed by
+    /// `read_message` before the inner stream is read. This should be called
+    /// after the buffer options and pool are set.
+    pub fn with_prefix(mut self, bytes: &[u8]) -> MessageReader<S> {
+        self.decoder.push(bytes);
+        self
+    }
+
+    /// Returns `true` if messages are read in the packed serialization format.
+    pub fn is_packed(&self) -> bool {
+        self.decoder.is_packed()
+    }
+
+    /// Returns `true` if the stream has been closed at a message boundary, and
+    /// all inbound messages have been read.
+    pub fn is_closed(&self) -> bool {
+        self.decoder.is_closed()
+    }
+
+    /// Returns `true` if a previous read error has poisoned the stream, so
+    /// that `read_message` fails.
+    pub fn is_read_poisoned(&self) -> bool {
+        self.decoder.is_poisoned()
     }
-    Ok(())
-}
 
-fn write_message<W>(write: &mut W,
-                    segment_table: &[u8],
-                    segments: &[&[Word]],
-                    write_progress: &mut (usize, usize))
-                    -> io::Result<()>
-where W: io::Write {
-    let (ref mut segment_index, ref mut segment_offset) = *write_progress;
+    /// Returns the inner stream.
+    pub fn inner_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
 
-    if *segment_index == 0 {
-        try!(write_segment(write, &segment_table[*segment_offset..], segment_offset));
-        *segment_offset = 0;
-        *segment_index += 1;
+    /// Returns the inner stream.
+    pub fn inner(&self) -> &S {
+        &self.inner
     }
 
-    for segment in &segments[(*segment_index - 1)..] {
-        try!(write_segment(write,
-                           &Word::words_to_bytes(segment)[*segment_offset..],
-                           segment_offset));
-        *segment_offset = 0;
-        *segment_index += 1;
+    /// Reunites the reader with a writer half, returning the original
+    /// `MessageStream` and the stream of the writer half.
+    ///
+    /// A shutdown pending from the writer's `close_write` is not retained; call
+    /// `close_write` on the reunited stream to shut it down.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the halves do not use the same serialization format.
+    pub fn reunite<W, M>(self, writer: MessageWriter<W, M>) -> (MessageStream<S, M>, W) {
+        assert_eq!(self.decoder.is_packed(), writer.encoder.is_packed(),
+                   "unable to reunite packed and unpacked message stream halves");
+        let MessageWriter { inner: write, encoder, .. } = writer;
+        let stream = MessageStream {
+            inner: self.inner,
+            decoder: self.decoder,
+            encoder: encoder,
+            read_paused: false,
+            shutdown_write: None,
+        };
+        (stream, write)
     }
-    Ok(())
 }
 
-impl <S, A, M> MessageStream<S, A, M> where S: io::Write, M: Borrow<Builder<A>>, A: Allocator {
+impl <S> MessageReader<S> where S: io::Read {
 
-    /// Writes queued messages to the stream. This should be called when the
-    /// stream is in non-blocking mode and writable.
+    /// Returns the next message from the stream, or `None` if the entire
+    /// message is not yet available, or if the stream has been closed.
+    ///
+    /// If the stream is closed at a message boundary, `None` is returned and
+    /// `is_closed` returns `true`. If the stream is closed in the middle of a
+    /// message, an `UnexpectedEof` error is returned.
+    ///
+    /// If an `Err` result is returned, then the stream is corrupt, and every
+    /// further call to `read_message` fails; see `is_read_poisoned`. The
+    /// exception is an error which reports a `SkippedMessage`, after which the
+    /// next message may be read.
+    pub fn read_message(&mut self) -> Result<Option<Reader<Segments>>> {
+        self.decoder.read_message(&mut self.inner)
+    }
+
+    /// Returns the segment table of the next message from the stream, once it
+    /// has been read, without reading the rest of the message. Returns `None`
+    /// if the segment table is not yet available, or if the stream has been
+    /// closed.
     ///
-    /// If an `Err` result is returned, then the stream must be considered
-    /// corrupt, and `write` or `write_message` must not be called again.
-    pub fn write(&mut self) -> io::Result<()> {
-
-        let MessageStream {
-            ref mut inner,
-            ref mut outbound_queue,
-            ref mut current_segment_table,
-            ref mut write_progress,
-            ..
-        } = *self;
+    /// The message itself is returned by a following call to `read_message`.
+    /// The message limits are checked when the segment table is read, so
+    /// errors are returned as by `read_message`.
+    pub fn peek_message_header(&mut self) -> Result<Option<MessageHeader>> {
+        self.decoder.peek_message_header(&mut self.inner)
+    }
+}
 
-        loop {
-            {
-                let message: &Builder<A> = match outbound_queue.front() {
-                    Some(message) => message.borrow(),
-                    None => return Ok(()),
-                };
+impl <S> fmt::Debug for MessageReader<S> where S: fmt::Debug {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "MessageReader {{ inner: {:?} }}", self.inner)
+    }
+}
 
-                *write_progress = write_progress.or_else(|| {
-                    serialize_segment_table(current_segment_table,
-                                            &*message.get_segments_for_output());
-                    Some((0, 0))
-                });
+/// The write half of a `MessageStream`.
+///
+/// A `MessageWriter` may be created directly, or by splitting a
+/// `MessageStream`.
+pub struct MessageWriter<S, M=Builder<HeapAllocator>> {
+    inner: S,
+    encoder: Encoder<M>,
+    /// Shuts down the write half of the inner stream, once the writer has been
+    /// closed by `close_write` and the outbound queue has drained.
+    shutdown_write: Option<fn(&mut S) -> io::Result<()>>,
+}
 
-                let progress: &mut (usize, usize) = write_progress.as_mut().unwrap();
-                let segments = &*message.get_segments_for_output();
+impl <S, M> MessageWriter<S, M> {
 
-                match write_message(inner, current_segment_table, segments, progress) {
-                    Err(ref error) if error.kind() == io::ErrorKind::WouldBlock => return Ok(()),
-                    Ok(_) => (),
-                    error => return error,
-                }
-            }
-            outbound_queue.pop_front();
-            *write_progress = None;
+    /// Creates a new `MessageWriter` instance wrapping the provided stream.
+    pub fn new(inner: S) -> MessageWriter<S, M> {
+        MessageWriter { inner: inner, encoder: Encoder::new(), shutdown_write: None }
+    }
+
+    /// Creates a new `MessageWriter` instance wrapping the provided stream.
+    /// Messages are written in the packed serialization format.
+    pub fn new_packed(inner: S) -> MessageWriter<S, M> {
+        MessageWriter { inner: inner, encoder: Encoder::new_packed(), shutdown_write: None }
+    }
+
+    /// Returns the number of queued outbound messages.
+    pub fn outbound_queue_len(&self) -> usize {
+        self.encoder.outbound_queue_len()
+    }
+
+    /// Returns the total length of the queued outbound messages, in bytes.
+    pub fn outbound_queue_bytes(&self) -> usize {
+        self.encoder.outbound_queue_bytes()
+    }
+
+    /// Returns `true` if the outbound queue is full, so that `write_message`
+    /// would hand the message back.
+    pub fn is_outbound_queue_full(&self) -> bool {
+        self.encoder.is_outbound_queue_full()
+    }
+
+    /// Returns `true` if the outbound queue is at or above its high watermark,
+    /// in which case the caller should stop producing outbound messages.
+    pub fn is_above_high_watermark(&self) -> bool {
+        self.encoder.is_above_high_watermark()
+    }
+
+    /// Returns `true` if the outbound queue is at or below its low watermark,
+    /// in which case the caller may resume producing outbound messages.
+    pub fn is_below_low_watermark(&self) -> bool {
+        self.encoder.is_below_low_watermark()
+    }
+
+    /// Clears the outbound message queue of all messages that have not begun
+    /// writing yet. The cleared messages are not completed.
+    pub fn clear_outbound_queue(&mut self) {
+        self.encoder.clear_outbound_queue()
+    }
+
+    /// Returns the completely written outbound messages which have been
+    /// retained since the previous call, so that they may be reused. Messages
+    /// are only retained if `QueueOptions::retain_written` is set.
+    pub fn take_written_messages(&mut self) -> Vec<M> {
+        self.encoder.take_written_messages()
+    }
+
+    /// Removes the queued outbound message with the provided ticket, and
+    /// returns it. Returns `None` if the message has begun writing, or is no
+    /// longer queued. A cancelled message is not completed.
+    pub fn cancel_message(&mut self, ticket: Ticket) -> Option<M> {
+        self.encoder.cancel_message(ticket)
+    }
+
+    /// Removes the queued outbound messages which have not begun writing and
+    /// for which the predicate returns `true`, and returns them in queue order.
+    /// Cancelled messages are not completed.
+    pub fn cancel_messages<F>(&mut self, predicate: F) -> Vec<M>
+    where F: FnMut(Ticket, &M) -> bool {
+        self.encoder.cancel_messages(predicate)
+    }
+
+    /// Returns `true` if messages are written in the packed serialization
+    /// format.
+    pub fn is_packed(&self) -> bool {
+        self.encoder.is_packed()
+    }
+
+    /// Returns `true` if a previous write error has poisoned the stream, so
+    /// that `write` and `write_message` fail.
+    pub fn is_write_poisoned(&self) -> bool {
+        self.encoder.is_poisoned()
+    }
+
+    /// Returns `true` if the stream has been closed for writing.
+    pub fn is_write_closed(&self) -> bool {
+        self.encoder.is_closed()
+    }
+
+    /// Returns `true` if the write half of the inner stream has been shut down.
+    pub fn is_shut_down(&self) -> bool {
+        self.encoder.is_shut_down()
+    }
+
+    /// Sets the options which limit the outbound message queue.
+    pub fn with_queue_options(mut self, options: QueueOptions) -> MessageWriter<S, M> {
+        self.encoder.set_queue_options(options);
+        self
+    }
+
+    /// Returns the inner stream.
+    pub fn inner_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    /// Returns the inner stream.
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl <S, M> MessageWriter<S, M> where S: io::Write, M: OutboundMessage {
+
+    /// Writes queued messages to the stream. This should be called when the
+    /// stream is in non-blocking mode and writable.
+    ///
+    /// Returns the tickets of the messages which have been completely written
+    /// since the previous call to `write`, including those written by
+    /// `write_message`.
+    ///
+    /// If the stream has been closed with `close_write`, the write half of the
+    /// inner stream is shut down once every queued message has been written.
+    ///
+    /// If an `Err` result is returned, then the stream is corrupt, and every
+    /// further call to `write` or `write_message` fails; see
+    /// `is_write_poisoned`.
+    pub fn write(&mut self) -> io::Result<Completed> {
+        let completed = try!(self.encoder.write(&mut self.inner));
+        if let Some(shutdown_write) = self.shutdown_write {
+            try!(self.encoder.shutdown_with(&mut self.inner, shutdown_write));
         }
+        Ok(completed)
     }
 
     /// Queue message for write.
     ///
     /// This method optimistically begins writing to the stream if there is no
-    /// message currently being written. This is necessary for the blocking
-    /// stream case, and efficient in the non-blocking case as well, since it is
-    /// likely that the stream is writable.
+    /// message currently being written.
     ///
-    /// If an `Err` result is returned, then the stream must be considered
-    /// corrupt, and `write` or `write_message` must not be called again.
-    pub fn write_message(&mut self, message: M) -> io::Result<()> {
-        self.outbound_queue.push_back(message);
-
-        if self.outbound_queue_len() == 1 {
-            // Swallow NotConnected error when aggressively writing. OS X will
-            // return NotConnected when writing to a freshly opened non-blocking
-            // socket; see hoverbear/raft#61.
-            match self.write() {
-                Err(ref error) if error.kind() == io::ErrorKind::NotConnected => Ok(()),
-                other => other,
-            }
-        } else {
-            Ok(())
-        }
+    /// Returns the ticket of the message, which is reported by `write` once the
+    /// message has been completely written.
+    ///
+    /// If the outbound queue is full, the message is not queued, and is handed
+    /// back with `WriteError::QueueFull`. If the stream has been closed for
+    /// writing, the message is handed back with `WriteError::Closed`, and if
+    /// the stream has been poisoned by a previous error, the message is handed
+    /// back with `WriteError::Poisoned`. Otherwise, if an `Err` result is
+    /// returned, then the stream is corrupt, and every further call to `write`
+    /// or `write_message` fails; see `is_write_poisoned`.
+    pub fn write_message(&mut self, message: M) -> result::Result<Ticket, WriteError<M>> {
+        self.encoder.write_message(&mut self.inner, message)
     }
 }
 
-/// Parses a segment table into a sequence of segment lengths, and adds the
-/// lengths to the provided `Vec`.
-///
-/// Returns 0 if the parse succeeded, otherwise returns the number of bytes
-/// required to make progress with the parse.
-fn parse_segment_table(buf: &[u8], lengths: &mut Vec<usize>) -> Result<usize> {
-    if buf.len() < 8 { return Ok(8); }
-    let segment_count = <LittleEndian as ByteOrder>::read_u32(&buf[0..4])
-                                                    .wrapping_add(1) as usize;
+impl <S, M> MessageWriter<S, M> where S: io::Write + ShutdownWrite, M: OutboundMessage {
 
-    if segment_count >= 512 {
-        return result::Result::Err(Error::new(ErrorKind::InvalidData,
-                                              format!("too many segments in Cap'n Proto message: {}",
-                                                      segment_count)));
-    } else if segment_count == 0 {
-        return result::Result::Err(Error::new(ErrorKind::InvalidData,
-                                              "zero segments Cap'n Proto message".to_string()));
+    /// Closes the stream for writing, so that `write_message` hands back
+    /// further messages with `WriteError::Closed`. Messages which have already
+    /// been queued are still written by `write`, and once the outbound queue
+    /// has drained, `write` shuts down the write half of the inner stream.
+    pub fn close_write(&mut self) {
+        self.encoder.close();
+        self.shutdown_write = Some(S::shutdown_write);
     }
 
-    let len = (segment_count / 2 + 1) * 8;
-    if buf.len() < len { return Ok(len); }
-
-    for segment in 0..segment_count {
-        let offset = (segment + 1) * 4;
-        let segment_len = <LittleEndian as ByteOrder>::read_u32(&buf[offset..]) as usize;
-        lengths.push(segment_len * 8);
+    /// Gracefully shuts down the write half of the stream. The stream is closed
+    /// for writing, queued messages are written, and once the outbound queue
+    /// has drained, the write half of the inner stream is shut down.
+    ///
+    /// Returns `true` once the write half has been shut down. Otherwise, queued
+    /// messages remain, and either `shutdown` or `write` should be called again
+    /// when the stream is writable.
+    pub fn shutdown(&mut self) -> io::Result<bool> {
+        self.close_write();
+        self.encoder.shutdown(&mut self.inner)
     }
+}
 
-    Ok(0)
+impl <S, M> fmt::Debug for MessageWriter<S, M> where S: fmt::Debug {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "MessageWriter {{ inner: {:?}, outbound_messages: {} }}",
+               self.inner, self.outbound_queue_len())
+    }
 }
 
 #[cfg(test)]
 pub mod test {
 
     use super::{
+        BufferOptions,
+        BufferPool,
+        Decoder,
+        Encoder,
+        FramedMessage,
+        Interest,
+        MessageReader,
+        LimitError,
+        MessageLimits,
         MessageStream,
-        parse_segment_table,
-        serialize_segment_table,
-        write_message,
+        MessageWriter,
+        OutboundMessage,
+        QueueOptions,
+        Segments,
+        SkippedMessage,
+        WriteError,
     };
+    use read::parse_segment_table;
+    use write::{serialize_segment_table, write_message};
 
-    use test_utils;
+    use packed;
+    use test_utils::{self, data_message};
 
-    use std::io::{self, Cursor, Write};
+    use std::{cmp, error};
+    use std::io::{self, Cursor, Read, Write};
+    use std::rc::Rc;
 
-    use capnp::{Word, message};
+    use capnp::{Word, data, message, serialize, serialize_packed};
     use capnp::message::ReaderSegments;
     use quickcheck::{quickcheck, TestResult};
 
@@ -414,7 +932,7 @@ pub mod test {
     fn test_parse_segment_table() {
         fn compare(expected: &[usize], buf: &[u8]) {
             let mut actual = Vec::new();
-            assert_eq!(0, parse_segment_table(buf, &mut actual).unwrap());
+            assert_eq!(0, parse_segment_table(buf, 511, &mut actual).unwrap());
             assert_eq!(expected, &*actual);
         }
 
@@ -450,11 +968,12 @@ pub mod test {
     #[test]
     fn test_parse_invalid_segment_table() {
         let mut v = Vec::new();
-        assert!(parse_segment_table(&[255,1,0,0,0,0,0,0], &mut v).is_err());
-        assert_eq!(8, parse_segment_table(&[0,0,0,0], &mut v).unwrap());
-        assert_eq!(8, parse_segment_table(&[0,0,0,0, 0,0,0], &mut v).unwrap());
-        assert_eq!(16, parse_segment_table(&[1,0,0,0, 0,0,0,0, 0,0,0], &mut v).unwrap());
-        assert!(parse_segment_table(&[255,255,255,255,0,0,0,0], &mut v).is_err());
+        assert!(parse_segment_table(&[255,1,0,0,0,0,0,0], 511, &mut v).is_err());
+        assert_eq!(8, parse_segment_table(&[0,0,0,0], 511, &mut v).unwrap());
+        assert_eq!(8, parse_segment_table(&[0,0,0,0, 0,0,0], 511, &mut v).unwrap());
+        assert_eq!(16, parse_segment_table(&[1,0,0,0, 0,0,0,0, 0,0,0], 511, &mut v).unwrap());
+        assert!(parse_segment_table(&[255,255,255,255,0,0,0,0], 511, &mut v).is_err());
+        assert!(parse_segment_table(&[2,0,0,0, 0,0,0,0, 0,0,0,0, 0,0,0,0], 2, &mut v).is_err());
     }
 
     #[test]
@@ -467,7 +986,7 @@ pub mod test {
             cursor.set_position(0);
 
             let mut message_reader =
-                MessageStream::<_, (), ()>::new(&mut cursor, message::ReaderOptions::new());
+                MessageStream::<_, ()>::new(&mut cursor, message::ReaderOptions::new());
             let message = message_reader.read_message().unwrap().unwrap();
             let result_segments = message.into_segments();
 
@@ -483,9 +1002,9 @@ pub mod test {
     /// of message objects, and automatically retries on `WouldBlock`.
     fn write_message_segments<W>(write: &mut W, segments: &Vec<Vec<Word>>)
     where W: Write {
-        let segments: &[&[Word]] = &segments.iter()
-                                            .map(|segment| &segment[..])
-                                            .collect::<Vec<_>>()[..];
+        let segments: &[&[u8]] = &segments.iter()
+                                          .map(|segment| Word::words_to_bytes(segment))
+                                          .collect::<Vec<_>>()[..];
         let mut segment_table = Vec::new();
         serialize_segment_table(&mut segment_table, segments);
         let mut write_progress = (0, 0);
@@ -528,7 +1047,7 @@ pub mod test {
             cursor.set_position(0);
 
             let mut message_reader =
-                MessageStream::<_, (), ()>::new(&mut cursor, message::ReaderOptions::new());
+                MessageStream::<_, ()>::new(&mut cursor, message::ReaderOptions::new());
 
             for segments in &messages {
                 let message = message_reader.read_message().unwrap().unwrap();
@@ -559,7 +1078,7 @@ pub mod test {
             stream.inner_mut().set_position(0);
 
             let mut message_reader =
-                MessageStream::<_, (), ()>::new(&mut stream, message::ReaderOptions::new());
+                MessageStream::<_, ()>::new(&mut stream, message::ReaderOptions::new());
 
             for segments in &messages {
                 let mut message = None;
@@ -579,4 +1098,1152 @@ pub mod test {
 
         quickcheck(round_trip_nonblock as fn(Vec<Vec<Vec<Word>>>, usize) -> TestResult);
     }
+
+    #[test]
+    fn check_read_packed_nonblock() {
+        fn read_packed(messages: Vec<Vec<u8>>, frequency: usize) -> TestResult {
+            if frequency == 0 { return TestResult::discard(); }
+
+            // Pack the messages with the reference implementation.
+            let mut packed = Vec::new();
+            for data in &messages {
+                serialize_packed::write_message(&mut packed, &data_message(data)).unwrap();
+            }
+
+            let mut stream = test_utils::BlockingStream::new(Cursor::new(packed), frequency);
+            let mut message_reader =
+                MessageStream::<_, ()>::new_packed(&mut stream, message::ReaderOptions::new());
+
+            for data in &messages {
+                let mut message = None;
+                while let None = message {
+                    message = message_reader.read_message().unwrap();
+                }
+                if &data[..] != message.unwrap().get_root::<data::Reader>().unwrap() {
+                    return TestResult::failed();
+                }
+            }
+            TestResult::passed()
+        }
+
+        quickcheck(read_packed as fn(Vec<Vec<u8>>, usize) -> TestResult);
+    }
+
+    #[test]
+    fn check_read_eof() {
+        fn read_eof(messages: Vec<Vec<u8>>, truncate: usize, frequency: usize, packed: bool) -> TestResult {
+            if frequency == 0 { return TestResult::discard(); }
+
+            // The offsets of the message boundaries in the stream. A packed
+            // message is only complete once its final packed byte is read.
+            let mut input = Vec::new();
+            let mut boundaries = vec![0];
+            for data in &messages {
+                if packed {
+                    serialize_packed::write_message(&mut input, &data_message(data)).unwrap();
+                } else {
+                    serialize::write_message(&mut input, &data_message(data)).unwrap();
+                }
+                boundaries.push(input.len());
+            }
+            let len = input.len() - truncate % (input.len() + 1);
+            input.truncate(len);
+
+            let mut stream = test_utils::BlockingStream::new(Cursor::new(input), frequency);
+            let mut message_reader = if packed {
+                MessageReader::new_packed(&mut stream, message::ReaderOptions::new())
+            } else {
+                MessageReader::new(&mut stream, message::ReaderOptions::new())
+            };
+
+            let mut count = 0;
+            let result = loop {
+                match message_reader.read_message() {
+                    Ok(Some(message)) => {
+                        if &messages[count][..] != message.get_root::<data::Reader>().unwrap() {
+                            return TestResult::failed();
+                        }
+                        count += 1;
+                    },
+                    Ok(None) if message_reader.is_closed() => break Ok(()),
+                    Ok(None) => (),
+                    Err(error) => break Err(error),
+                }
+            };
+
+            let expected_count = boundaries.iter().filter(|&&boundary| boundary != 0 && boundary <= len).count();
+            let clean = boundaries.contains(&len);
+            TestResult::from_bool(count == expected_count && match result {
+                Ok(()) => clean && message_reader.read_message().unwrap().is_none(),
+                Err(ref error) => !clean && error.kind() == io::ErrorKind::UnexpectedEof,
+            })
+        }
+
+        quickcheck(read_eof as fn(Vec<Vec<u8>>, usize, usize, bool) -> TestResult);
+    }
+
+    #[test]
+    fn check_write_packed_nonblock() {
+        fn write_packed(messages: Vec<Vec<u8>>, frequency: usize) -> TestResult {
+            if frequency == 0 { return TestResult::discard(); }
+            let stream = test_utils::BlockingStream::new(Cursor::new(Vec::new()), frequency);
+            let mut message_writer = MessageStream::new_packed(stream, message::ReaderOptions::new());
+
+            let mut expected = Vec::new();
+            for data in &messages {
+                let message = data_message(data);
+                serialize::write_message(&mut expected, &message).unwrap();
+                message_writer.write_message(message).unwrap();
+            }
+            while message_writer.outbound_queue_len() > 0 {
+                message_writer.write().unwrap();
+            }
+
+            let mut packed = Cursor::new(message_writer.inner_mut().inner_mut().get_ref().clone());
+            let mut unpacked = Vec::new();
+            packed::Unpacker::new().reader(&mut packed).read_to_end(&mut unpacked).unwrap();
+            if expected != unpacked {
+                return TestResult::failed();
+            }
+
+            // The reference implementation reads the packed messages.
+            packed.set_position(0);
+            for data in &messages {
+                let message = serialize_packed::read_message(&mut packed, message::ReaderOptions::new()).unwrap();
+                if &data[..] != message.get_root::<data::Reader>().unwrap() {
+                    return TestResult::failed();
+                }
+            }
+            TestResult::passed()
+        }
+
+        quickcheck(write_packed as fn(Vec<Vec<u8>>, usize) -> TestResult);
+    }
+
+    #[test]
+    fn test_packed_outbound_slices() {
+        let mut encoder = Encoder::new_packed();
+        let mut expected = Vec::new();
+        for data in &[&b"abcdefgh"[..], &b"ijklmnop"[..], &b"qrstuvwx"[..]] {
+            let message = data_message(data);
+            serialize::write_message(&mut expected, &message).unwrap();
+            encoder.queue_message(message).ok().unwrap();
+        }
+
+        // The segment table and segment of every queued message are packed
+        // into a single batch of slices.
+        let packed = {
+            let slices = encoder.outbound_slices();
+            assert_eq!(6, slices.len());
+            slices.iter().fold(Vec::new(), |mut acc, slice| { acc.extend_from_slice(slice); acc })
+        };
+        encoder.consume(packed.len());
+        assert_eq!(0, encoder.outbound_queue_len());
+
+        let mut unpacked = Vec::new();
+        packed::Unpacker::new().reader(&mut Cursor::new(packed)).read_to_end(&mut unpacked).unwrap();
+        assert_eq!(expected, unpacked);
+    }
+
+    #[test]
+    fn check_split_reunite_nonblock() {
+        fn split_reunite(messages: Vec<Vec<u8>>, frequency: usize) -> TestResult {
+            if frequency == 0 { return TestResult::discard(); }
+
+            let mut input = Vec::new();
+            for data in &messages {
+                serialize::write_message(&mut input, &data_message(data)).unwrap();
+            }
+            let stream = test_utils::BlockingStream::new(Cursor::new(input), frequency);
+            let mut message_stream = MessageStream::new(stream, message::ReaderOptions::new());
+
+            // Begin reading before splitting, so that the partially read
+            // message is carried over to the reader half.
+            let mut message = if messages.is_empty() {
+                None
+            } else {
+                message_stream.read_message().unwrap()
+            };
+
+            let (mut reader, mut writer) = message_stream.split(Vec::new());
+
+            let mut expected = Vec::new();
+            for data in &messages {
+                while let None = message {
+                    message = reader.read_message().unwrap();
+                }
+                if &data[..] != message.take().unwrap().get_root::<data::Reader>().unwrap() {
+                    return TestResult::failed();
+                }
+
+                let response = data_message(data);
+                serialize::write_message(&mut expected, &response).unwrap();
+                writer.write_message(response).unwrap();
+            }
+
+            let (message_stream, written) = reader.reunite(writer);
+            TestResult::from_bool(expected == written && message_stream.outbound_queue_len() == 0)
+        }
+
+        quickcheck(split_reunite as fn(Vec<Vec<u8>>, usize) -> TestResult);
+    }
+
+    #[test]
+    fn check_write_nonblock() {
+        fn write(messages: Vec<Vec<u8>>, frequency: usize) -> TestResult {
+            if frequency == 0 { return TestResult::discard(); }
+            let stream = test_utils::BlockingStream::new(Cursor::new(Vec::new()), frequency);
+            let mut message_writer = MessageStream::new(stream, message::ReaderOptions::new());
+
+            let mut expected = Vec::new();
+            for data in &messages {
+                // Allocate small segments, so that messages span many segments.
+                let allocator = message::HeapAllocator::new()
+                    .first_segment_words(1)
+                    .allocation_strategy(message::AllocationStrategy::FixedSize);
+                let mut message = message::Builder::new(allocator);
+                message.set_root::<data::Builder, _>(&data[..]).unwrap();
+
+                serialize::write_message(&mut expected, &message).unwrap();
+                message_writer.write_message(message).unwrap();
+            }
+            while message_writer.outbound_queue_len() > 0 {
+                message_writer.write().unwrap();
+            }
+
+            TestResult::from_bool(&expected == message_writer.inner_mut().inner_mut().get_ref())
+        }
+
+        quickcheck(write as fn(Vec<Vec<u8>>, usize) -> TestResult);
+    }
+
+    #[cfg(feature = "sync")]
+    #[test]
+    fn segments_are_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        fn assert_send<T: Send>() {}
+        assert_send_sync::<super::Segments>();
+        assert_send::<message::Reader<super::Segments>>();
+    }
+
+    #[test]
+    fn check_round_trip_buffer_options() {
+        fn round_trip(messages: Vec<Vec<u8>>,
+                      initial_size: usize,
+                      min_size: usize,
+                      adaptive: bool)
+                      -> TestResult {
+            let mut input = Vec::new();
+            for data in &messages {
+                serialize::write_message(&mut input, &data_message(data)).unwrap();
+            }
+
+            let mut buffer_options = BufferOptions::new();
+            buffer_options.initial_size(initial_size)
+                          .min_size(min_size)
+                          .max_size(min_size * 4)
+                          .adaptive(adaptive);
+            let mut message_reader =
+                MessageReader::new(Cursor::new(input), message::ReaderOptions::new())
+                              .with_buffer_options(buffer_options);
+
+            for data in &messages {
+                let message = message_reader.read_message().unwrap().unwrap();
+                if &data[..] != message.get_root::<data::Reader>().unwrap() {
+                    return TestResult::failed();
+                }
+            }
+            TestResult::passed()
+        }
+
+        quickcheck(round_trip as fn(Vec<Vec<u8>>, usize, usize, bool) -> TestResult);
+    }
+
+    #[test]
+    fn check_read_buffer_pool() {
+        fn read_pool(messages: Vec<Vec<u8>>, readers: usize) -> TestResult {
+            if readers == 0 { return TestResult::discard(); }
+            let mut input = Vec::new();
+            for data in &messages {
+                serialize::write_message(&mut input, &data_message(data)).unwrap();
+            }
+
+            let pool = BufferPool::new(4);
+            let mut buffer_options = BufferOptions::new();
+            buffer_options.initial_size(64).min_size(64);
+
+            let mut read_messages = Vec::new();
+            for _ in 0..readers {
+                let mut message_reader =
+                    MessageReader::new(Cursor::new(&input[..]), message::ReaderOptions::new())
+                                  .with_buffer_pool(pool.clone())
+                                  .with_buffer_options(buffer_options);
+                for data in &messages {
+                    let message = message_reader.read_message().unwrap().unwrap();
+                    if &data[..] != message.get_root::<data::Reader>().unwrap() {
+                        return TestResult::failed();
+                    }
+                    read_messages.push(message);
+                }
+            }
+
+            // All buffers are returned to the pool once the messages are dropped.
+            drop(read_messages);
+            TestResult::from_bool(pool.stats().in_use == 0 && pool.stats().free <= 4)
+        }
+
+        quickcheck(read_pool as fn(Vec<Vec<u8>>, usize) -> TestResult);
+    }
+
+    #[test]
+    fn check_encode_decode() {
+        fn encode_decode(messages: Vec<Vec<u8>>, chunk_len: usize, packed: bool) -> TestResult {
+            if chunk_len == 0 { return TestResult::discard(); }
+            let (mut encoder, mut decoder) = if packed {
+                (Encoder::new_packed(), Decoder::new_packed(message::ReaderOptions::new()))
+            } else {
+                (Encoder::new(), Decoder::new(message::ReaderOptions::new()))
+            };
+
+            for data in &messages {
+                assert!(encoder.queue_message(data_message(data)).is_ok());
+            }
+
+            // Transfer at most `chunk_len` bytes at a time from the encoder to
+            // the decoder.
+            let mut decoded = Vec::new();
+            loop {
+                let n = {
+                    let slices = encoder.outbound_slices();
+                    if slices.is_empty() { break; }
+                    let mut chunk = Vec::new();
+                    for slice in &slices {
+                        let n = cmp::min(slice.len(), chunk_len - chunk.len());
+                        chunk.extend_from_slice(&slice[..n]);
+                    }
+                    decoder.push(&chunk);
+                    chunk.len()
+                };
+                encoder.consume(n);
+
+                while let Some(message) = decoder.next_message().unwrap() {
+                    decoded.push(message.get_root::<data::Reader>().unwrap().to_vec());
+                }
+            }
+
+            TestResult::from_bool(encoder.outbound_queue_len() == 0 && messages == decoded)
+        }
+
+        quickcheck(encode_decode as fn(Vec<Vec<u8>>, usize, bool) -> TestResult);
+    }
+
+    #[test]
+    fn test_push_packed_large_message() {
+        // A long run of zero words packs to a few bytes, which are pushed into
+        // the decoder at once.
+        let data = vec![0; 8 << 20];
+        let mut packed = Vec::new();
+        serialize_packed::write_message(&mut packed, &data_message(&data)).unwrap();
+        assert!(packed.len() < 64 * 1024);
+
+        let mut decoder = Decoder::new_packed(*message::ReaderOptions::new().traversal_limit_in_words(2 << 20));
+        decoder.push(&packed);
+        let message = decoder.next_message().unwrap().unwrap();
+        assert_eq!(&data[..], message.get_root::<data::Reader>().unwrap());
+    }
+
+    #[test]
+    fn test_outbound_queue_limits() {
+        // The stream blocks on the first write, so that messages remain queued.
+        let stream = test_utils::BlockingStream::new(Cursor::new(Vec::new()), 1 << 20);
+        let mut writer = MessageWriter::new(stream);
+
+        writer.write_message(data_message(b"abcdefgh")).unwrap();
+        let len = writer.outbound_queue_bytes();
+        assert_eq!(24, len);
+
+        let mut options = QueueOptions::new();
+        options.max_messages(3)
+               .max_bytes(len * 4)
+               .high_watermark(len * 2)
+               .low_watermark(len);
+        let mut writer = writer.with_queue_options(options);
+
+        assert!(writer.is_below_low_watermark());
+        writer.write_message(data_message(b"abcdefgh")).unwrap();
+        assert!(writer.is_above_high_watermark());
+        assert!(!writer.is_below_low_watermark());
+        writer.write_message(data_message(b"abcdefgh")).unwrap();
+        assert!(writer.is_outbound_queue_full());
+
+        match writer.write_message(data_message(b"ijklmnop")) {
+            Err(WriteError::QueueFull(message)) => {
+                assert_eq!(b"ijklmnop", message.get_root_as_reader::<data::Reader>().unwrap());
+            },
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!(3, writer.outbound_queue_len());
+        assert_eq!(len * 3, writer.outbound_queue_bytes());
+
+        writer.write().unwrap();
+        assert_eq!(0, writer.outbound_queue_len());
+        assert_eq!(0, writer.outbound_queue_bytes());
+        assert!(writer.is_below_low_watermark());
+
+        // A message which exceeds the byte limit is queued if the queue is
+        // not yet full, and fills it.
+        let stream = test_utils::BlockingStream::new(Cursor::new(Vec::new()), 1 << 20);
+        let mut writer = MessageWriter::new(stream).with_queue_options(options);
+        writer.write_message(data_message(&[1; 128])).unwrap();
+        assert!(writer.outbound_queue_bytes() > len * 4);
+        assert!(writer.is_outbound_queue_full());
+        match writer.write_message(data_message(b"abcdefgh")) {
+            Err(WriteError::QueueFull(_)) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        let stream = test_utils::BlockingStream::new(Cursor::new(Vec::new()), 1 << 20);
+        let mut writer = MessageWriter::new(stream).with_queue_options(options);
+        writer.write_message(data_message(b"abcdefgh")).unwrap();
+        assert!(!writer.is_outbound_queue_full());
+        writer.write_message(data_message(&[1; 128])).unwrap();
+        assert!(writer.outbound_queue_bytes() > len * 4);
+        assert!(writer.is_outbound_queue_full());
+    }
+
+    /// A stream which fails every read and write.
+    struct FailingStream;
+
+    impl Read for FailingStream {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::ConnectionReset, "FailingStream"))
+        }
+    }
+
+    impl Write for FailingStream {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::ConnectionReset, "FailingStream"))
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_poisoned_read() {
+        // A segment table with too many segments.
+        let mut message_reader = MessageStream::<_, ()>::new(Cursor::new(vec![255, 1, 0, 0, 0, 0, 0, 0]),
+                                                                 message::ReaderOptions::new());
+        assert!(!message_reader.is_read_poisoned());
+        assert_eq!(io::ErrorKind::InvalidData, message_reader.read_message().err().unwrap().kind());
+        assert!(message_reader.is_read_poisoned());
+        assert!(!message_reader.is_write_poisoned());
+
+        let error = message_reader.read_message().err().unwrap();
+        assert_eq!(io::ErrorKind::InvalidData, error.kind());
+        assert!(error.to_string().contains("poisoned"));
+
+        let mut message_reader = MessageReader::new(FailingStream, message::ReaderOptions::new());
+        assert_eq!(io::ErrorKind::ConnectionReset, message_reader.read_message().err().unwrap().kind());
+        assert!(message_reader.is_read_poisoned());
+        assert!(message_reader.read_message().is_err());
+    }
+
+    #[test]
+    fn test_poisoned_write() {
+        let mut message_writer = MessageWriter::new(FailingStream);
+        match message_writer.write_message(data_message(b"abcdefgh")) {
+            Err(WriteError::Io(ref error)) if error.kind() == io::ErrorKind::ConnectionReset => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert!(message_writer.is_write_poisoned());
+        assert_eq!(1, message_writer.outbound_queue_len());
+
+        assert!(message_writer.write().is_err());
+        match message_writer.write_message(data_message(b"ijklmnop")) {
+            Err(WriteError::Poisoned(message, ref error)) => {
+                assert_eq!(b"ijklmnop", message.get_root_as_reader::<data::Reader>().unwrap());
+                assert!(error.to_string().contains("poisoned"));
+            },
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!(1, message_writer.outbound_queue_len());
+    }
+
+    #[test]
+    fn test_message_limits() {
+        fn read_limit_error(data: &[u8], limits: &MessageLimits) -> Option<LimitError> {
+            let mut input = Vec::new();
+            serialize::write_message(&mut input, &data_message(data)).unwrap();
+            let mut message_reader = MessageReader::new(Cursor::new(input), message::ReaderOptions::new())
+                                                   .with_message_limits(*limits);
+            match message_reader.read_message() {
+                Ok(message) => {
+                    assert!(message.is_some());
+                    None
+                },
+                Err(error) => {
+                    assert_eq!(io::ErrorKind::InvalidData, error.kind());
+                    Some(*error.get_ref().unwrap().downcast_ref::<LimitError>().unwrap())
+                },
+            }
+        }
+
+        // A message with 64 bytes of data has a 72 byte segment, and an 8 byte
+        // segment table.
+        let data = &[7; 64];
+        let mut limits = MessageLimits::new();
+        assert_eq!(None, read_limit_error(data, &limits));
+
+        limits.max_segments(0);
+        assert_eq!(Some(LimitError::TooManySegments { count: 1, limit: 0 }),
+                   read_limit_error(data, &limits));
+
+        limits.max_segments(1).max_segment_size(64);
+        assert_eq!(Some(LimitError::SegmentTooLarge { len: 72, limit: 64 }),
+                   read_limit_error(data, &limits));
+
+        limits.max_segment_size(72).max_message_size(79);
+        assert_eq!(Some(LimitError::MessageTooLarge { len: 80, limit: 79 }),
+                   read_limit_error(data, &limits));
+
+        limits.max_message_size(80);
+        assert_eq!(None, read_limit_error(data, &limits));
+    }
+
+    #[test]
+    fn check_skip_oversized() {
+        fn skip_oversized(messages: Vec<Vec<u8>>, max_message_size: usize, frequency: usize) -> TestResult {
+            if frequency == 0 { return TestResult::discard(); }
+
+            let mut input = Vec::new();
+            let mut lens = Vec::new();
+            for data in &messages {
+                let offset = input.len();
+                serialize::write_message(&mut input, &data_message(data)).unwrap();
+                lens.push(input.len() - offset);
+            }
+
+            let mut limits = MessageLimits::new();
+            limits.max_message_size(max_message_size).skip_oversized(true);
+            let stream = test_utils::BlockingStream::new(Cursor::new(input), frequency);
+            let mut buffer_options = BufferOptions::new();
+            buffer_options.initial_size(64).min_size(64);
+            let mut message_reader = MessageReader::new(stream, message::ReaderOptions::new())
+                                                   .with_message_limits(limits)
+                                                   .with_buffer_options(buffer_options);
+
+            for (data, &len) in messages.iter().zip(&lens) {
+                let result = loop {
+                    match message_reader.read_message() {
+                        Ok(None) => continue,
+                        other => break other,
+                    }
+                };
+                match result {
+                    Ok(Some(message)) => {
+                        if len > max_message_size
+                            || &data[..] != message.get_root::<data::Reader>().unwrap() {
+                            return TestResult::failed();
+                        }
+                    },
+                    Err(error) => {
+                        let skipped = error.get_ref().unwrap().downcast_ref::<SkippedMessage>().unwrap();
+                        let cause = error::Error::source(skipped).unwrap().downcast_ref::<LimitError>();
+                        if len <= max_message_size
+                            || skipped.len != len
+                            || cause != Some(&skipped.cause)
+                            || message_reader.is_read_poisoned() {
+                            return TestResult::failed();
+                        }
+                    },
+                    Ok(None) => unreachable!(),
+                }
+            }
+            TestResult::passed()
+        }
+
+        quickcheck(skip_oversized as fn(Vec<Vec<u8>>, usize, usize) -> TestResult);
+    }
+
+    #[test]
+    fn check_peek_message_header() {
+        fn peek(messages: Vec<Vec<Vec<Word>>>, frequency: usize) -> TestResult {
+            if frequency == 0 { return TestResult::discard(); }
+            let mut stream = test_utils::BlockingStream::new(Cursor::new(Vec::new()), frequency);
+            for segments in &messages {
+                if segments.len() == 0 { return TestResult::discard(); }
+                write_message_segments(&mut stream, segments);
+            }
+            stream.inner_mut().set_position(0);
+
+            let mut message_reader =
+                MessageStream::<_, ()>::new(&mut stream, message::ReaderOptions::new());
+
+            for segments in &messages {
+                let mut header = None;
+                while let None = header {
+                    header = message_reader.peek_message_header().unwrap();
+                }
+                let header = header.unwrap();
+                let segment_lens = segments.iter().map(|segment| segment.len() * 8).collect::<Vec<_>>();
+                if header.segment_count() != segments.len() || header.segment_lens() != &segment_lens[..] {
+                    return TestResult::failed();
+                }
+                let message_len = (segments.len() / 2 + 1) * 8 + segment_lens.iter().sum::<usize>();
+                if header.message_len() != message_len {
+                    return TestResult::failed();
+                }
+
+                // Peeking again returns the same header.
+                if message_reader.peek_message_header().unwrap() != Some(header) {
+                    return TestResult::failed();
+                }
+
+                let mut message = None;
+                while let None = message {
+                    message = message_reader.read_message().unwrap();
+                }
+                let result_segments = message.unwrap().into_segments();
+                for (i, segment) in segments.into_iter().enumerate() {
+                    if &segment[..] != result_segments.get_segment(i as u32).unwrap() {
+                        return TestResult::failed();
+                    }
+                }
+            }
+            let mut header = None;
+            while header.is_none() && !message_reader.is_closed() {
+                header = message_reader.peek_message_header().unwrap();
+            }
+            TestResult::from_bool(header.is_none())
+        }
+
+        quickcheck(peek as fn(Vec<Vec<Vec<Word>>>, usize) -> TestResult);
+    }
+
+    #[test]
+    fn check_completed_tickets() {
+        fn completed(messages: Vec<Vec<u8>>, chunk_len: usize, packed: bool) -> TestResult {
+            if chunk_len == 0 { return TestResult::discard(); }
+            let mut encoder = if packed { Encoder::new_packed() } else { Encoder::new() };
+
+            let mut tickets = Vec::new();
+            for data in &messages {
+                tickets.push(encoder.queue_message(data_message(data)).ok().unwrap());
+            }
+
+            // Consume at most `chunk_len` bytes at a time. A ticket is only
+            // completed once its message has been written, and only once.
+            let mut completed = Vec::new();
+            loop {
+                let n = {
+                    let slices = encoder.outbound_slices();
+                    if slices.is_empty() { break; }
+                    cmp::min(chunk_len, slices.iter().fold(0, |acc, slice| acc + slice.len()))
+                };
+                encoder.consume(n);
+                let written = messages.len() - encoder.outbound_queue_len();
+                completed.extend(encoder.take_completed());
+                if completed[..] != tickets[..written] {
+                    return TestResult::failed();
+                }
+            }
+            completed.extend(encoder.take_completed());
+
+            TestResult::from_bool(completed == tickets && encoder.take_completed().is_empty())
+        }
+
+        quickcheck(completed as fn(Vec<Vec<u8>>, usize, bool) -> TestResult);
+    }
+
+    #[test]
+    fn test_write_completed_tickets() {
+        let stream = test_utils::BlockingStream::new(Cursor::new(Vec::new()), 1 << 20);
+        let mut writer = MessageWriter::new(stream);
+
+        // The first message blocks, and the second is queued behind it.
+        let first = writer.write_message(data_message(b"abcdefgh")).unwrap();
+        let second = writer.write_message(data_message(b"ijklmnop")).unwrap();
+        assert!(first < second);
+
+        let completed = writer.write().unwrap();
+        assert!(completed.contains(first) && completed.contains(second));
+        assert_eq!(vec![first, second], completed.collect::<Vec<_>>());
+        assert!(writer.write().unwrap().is_empty());
+
+        // Messages written optimistically by `write_message` are reported by
+        // the next call to `write`.
+        let third = writer.write_message(data_message(b"qrstuvwx")).unwrap();
+        assert_eq!(0, writer.outbound_queue_len());
+        assert_eq!(vec![third], writer.write().unwrap().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_cancel_messages() {
+        // The stream writes 8 bytes at a time, so that the first message is
+        // partially written.
+        let stream = test_utils::BlockingStream::new(Cursor::new(Vec::new()), 8);
+        let mut writer = MessageWriter::new(stream);
+
+        let tickets = [b"abcdefgh", b"ijklmnop", b"qrstuvwx", b"yz012345"].iter().map(|data| {
+            writer.write_message(data_message(*data)).unwrap()
+        }).collect::<Vec<_>>();
+        writer.write().unwrap();
+        assert_eq!(4, writer.outbound_queue_len());
+
+        // The partially written message is protected.
+        assert!(writer.cancel_message(tickets[0]).is_none());
+        let cancelled = writer.cancel_message(tickets[2]).unwrap();
+        assert_eq!(b"qrstuvwx", cancelled.get_root_as_reader::<data::Reader>().unwrap());
+        assert!(writer.cancel_message(tickets[2]).is_none());
+        assert_eq!(3, writer.outbound_queue_len());
+        assert_eq!(72, writer.outbound_queue_bytes());
+
+        let cancelled = writer.cancel_messages(|ticket, _| ticket != tickets[1]);
+        assert_eq!(1, cancelled.len());
+        assert_eq!(b"yz012345", cancelled[0].get_root_as_reader::<data::Reader>().unwrap());
+        assert_eq!(48, writer.outbound_queue_bytes());
+
+        // Cancelled messages are not completed.
+        let mut completed = Vec::new();
+        while writer.outbound_queue_len() > 0 {
+            completed.extend(writer.write().unwrap());
+        }
+        assert_eq!(vec![tickets[0], tickets[1]], completed);
+
+        let mut expected = Vec::new();
+        serialize::write_message(&mut expected, &data_message(b"abcdefgh")).unwrap();
+        serialize::write_message(&mut expected, &data_message(b"ijklmnop")).unwrap();
+        assert_eq!(&expected, writer.inner_mut().inner_mut().get_ref());
+
+        // Nor are cleared messages.
+        let stream = test_utils::BlockingStream::new(Cursor::new(Vec::new()), 8);
+        let mut writer = MessageWriter::new(stream);
+        let first = writer.write_message(data_message(b"abcdefgh")).unwrap();
+        writer.write_message(data_message(b"ijklmnop")).unwrap();
+        let mut completed = writer.write().unwrap().collect::<Vec<_>>();
+        writer.clear_outbound_queue();
+        assert_eq!(1, writer.outbound_queue_len());
+        while writer.outbound_queue_len() > 0 {
+            completed.extend(writer.write().unwrap());
+        }
+        assert_eq!(vec![first], completed);
+    }
+
+    #[test]
+    fn test_retain_written_messages() {
+        let mut writer = MessageWriter::new(Cursor::new(Vec::new()));
+        writer.write_message(data_message(b"abcdefgh")).unwrap();
+        assert!(writer.take_written_messages().is_empty());
+
+        let mut options = QueueOptions::new();
+        options.retain_written(true);
+        let mut writer = writer.with_queue_options(options);
+        writer.write_message(data_message(b"ijklmnop")).unwrap();
+        writer.write_message(data_message(b"qrstuvwx")).unwrap();
+
+        let written = writer.take_written_messages();
+        assert_eq!(2, written.len());
+        assert_eq!(b"ijklmnop", written[0].get_root_as_reader::<data::Reader>().unwrap());
+        assert_eq!(b"qrstuvwx", written[1].get_root_as_reader::<data::Reader>().unwrap());
+        assert!(writer.take_written_messages().is_empty());
+
+        // A retained message may be rebuilt and written again.
+        let mut message = written.into_iter().next().unwrap();
+        message.set_root::<data::Builder, _>(&b"yz012345"[..]).unwrap();
+        writer.write_message(message).unwrap();
+
+        let mut input = Cursor::new(writer.inner().get_ref().clone());
+        for data in &[b"abcdefgh", b"ijklmnop", b"qrstuvwx", b"yz012345"] {
+            let message = serialize::read_message(&mut input, message::ReaderOptions::new()).unwrap();
+            assert_eq!(&data[..], message.get_root::<data::Reader>().unwrap());
+        }
+    }
+
+    #[test]
+    fn test_write_outbound_messages() {
+        let mut framed = Vec::new();
+        serialize::write_message(&mut framed, &data_message(b"ijklmnop")).unwrap();
+        assert!(FramedMessage::new(framed[..framed.len() - 8].to_vec()).is_err());
+        let framed = FramedMessage::new(framed).unwrap();
+
+        let mut received = Vec::new();
+        serialize::write_message(&mut received, &data_message(b"yz012345")).unwrap();
+        let received = MessageStream::<_, ()>::new(Cursor::new(received), message::ReaderOptions::new())
+                                    .read_message().unwrap().unwrap();
+
+        let messages: Vec<Box<dyn OutboundMessage>> = vec![
+            Box::new(data_message(b"abcdefgh")),
+            Box::new(framed),
+            Box::new(Rc::new(data_message(b"qrstuvwx"))),
+            Box::new(received.into_segments()),
+        ];
+        let mut writer = MessageWriter::new(Cursor::new(Vec::new()));
+        for message in messages {
+            writer.write_message(message).ok().unwrap();
+        }
+        assert_eq!(0, writer.outbound_queue_len());
+
+        let mut input = Cursor::new(writer.inner().get_ref().clone());
+        for data in &[b"abcdefgh", b"ijklmnop", b"qrstuvwx", b"yz012345"] {
+            let message = serialize::read_message(&mut input, message::ReaderOptions::new()).unwrap();
+            assert_eq!(&data[..], message.get_root::<data::Reader>().unwrap());
+        }
+    }
+
+    #[test]
+    fn test_forward_segments() {
+        let mut input = Vec::new();
+        for data in &[b"abcdefgh", b"ijklmnop"] {
+            serialize::write_message(&mut input, &data_message(*data)).unwrap();
+        }
+
+        let mut reader =
+            MessageReader::new(Cursor::new(input.clone()), message::ReaderOptions::new());
+        let mut options = QueueOptions::new();
+        options.retain_written(true);
+        let stream = test_utils::BlockingStream::new(Cursor::new(Vec::new()), 8);
+        let mut writer = MessageWriter::<_, Segments>::new(stream).with_queue_options(options);
+        let mut addresses = Vec::new();
+        while let Some(message) = reader.read_message().unwrap() {
+            let segments = Segments::from(message);
+            addresses.push(segments.get_segment(0).unwrap().as_ptr());
+            writer.write_message(segments).ok().unwrap();
+        }
+        while writer.outbound_queue_len() > 0 {
+            writer.write().unwrap();
+        }
+
+        // The written segments are the read buffers themselves.
+        let written = writer.take_written_messages();
+        assert_eq!(addresses, written.iter().map(|segments| {
+            segments.get_segment(0).unwrap().as_ptr()
+        }).collect::<Vec<_>>());
+        assert_eq!(&input, writer.inner_mut().inner_mut().get_ref());
+    }
+
+    #[test]
+    fn test_shutdown() {
+        let stream = test_utils::BlockingStream::new(Cursor::new(Vec::new()), 8);
+        let mut writer = MessageWriter::new(stream);
+        writer.write_message(data_message(b"abcdefgh")).unwrap();
+        writer.write_message(data_message(b"ijklmnop")).unwrap();
+
+        writer.close_write();
+        assert!(writer.is_write_closed());
+        match writer.write_message(data_message(b"qrstuvwx")) {
+            Err(WriteError::Closed(message)) => {
+                assert_eq!(b"qrstuvwx", message.get_root_as_reader::<data::Reader>().unwrap());
+            },
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!(2, writer.outbound_queue_len());
+
+        // The queue is drained before the write half is shut down.
+        writer.write().unwrap();
+        while !writer.shutdown().unwrap() {
+            assert!(!writer.inner_mut().is_shut_down());
+        }
+        assert!(writer.inner_mut().is_shut_down());
+        assert_eq!(0, writer.outbound_queue_len());
+        assert!(writer.shutdown().unwrap());
+
+        let mut expected = Vec::new();
+        serialize::write_message(&mut expected, &data_message(b"abcdefgh")).unwrap();
+        serialize::write_message(&mut expected, &data_message(b"ijklmnop")).unwrap();
+        assert_eq!(&expected, writer.inner_mut().inner_mut().get_ref());
+    }
+
+    #[test]
+    fn test_close_write_shuts_down_on_write() {
+        let stream = test_utils::BlockingStream::new(Cursor::new(Vec::new()), 8);
+        let mut stream = MessageStream::new(stream, message::ReaderOptions::new());
+        stream.write_message(data_message(b"abcdefgh")).unwrap();
+        stream.close_write();
+
+        // The write half is shut down by `write` once the queue has drained.
+        while stream.wants_write() {
+            assert!(!stream.is_shut_down());
+            stream.write().unwrap();
+        }
+        assert!(stream.is_shut_down());
+        assert!(stream.inner_mut().is_shut_down());
+        assert_eq!(0, stream.outbound_queue_len());
+
+        let mut expected = Vec::new();
+        serialize::write_message(&mut expected, &data_message(b"abcdefgh")).unwrap();
+        assert_eq!(&expected, stream.inner_mut().inner_mut().get_ref());
+    }
+
+    #[cfg(feature = "mio")]
+    #[test]
+    fn test_close_write_mio() {
+        use std::net::{TcpListener, TcpStream};
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        server.set_nonblocking(true).unwrap();
+
+        let mut writer = MessageWriter::new(::mio::net::TcpStream::from_std(server));
+        writer.write_message(data_message(b"abcdefgh")).unwrap();
+        writer.close_write();
+        while !writer.is_shut_down() {
+            writer.write().unwrap();
+        }
+
+        // The client reads the message, and then the end of the stream.
+        let message = serialize::read_message(&mut client, message::ReaderOptions::new()).unwrap();
+        assert_eq!(b"abcdefgh", message.get_root::<data::Reader>().unwrap());
+        assert_eq!(0, client.read(&mut [0; 8]).unwrap());
+    }
+
+    #[test]
+    fn check_into_parts() {
+        fn into_parts(messages: Vec<Vec<u8>>, split: usize, chunk_len: usize, packed: bool) -> TestResult {
+            if chunk_len == 0 { return TestResult::discard(); }
+            let new_encoder = || if packed { Encoder::new_packed() } else { Encoder::new() };
+            let new_decoder = || if packed {
+                Decoder::new_packed(message::ReaderOptions::new())
+            } else {
+                Decoder::new(message::ReaderOptions::new())
+            };
+            let encode = |encoder: &mut Encoder, len: usize| {
+                let mut bytes = Vec::new();
+                while bytes.len() < len {
+                    let n = {
+                        let slices = encoder.outbound_slices();
+                        if slices.is_empty() { break; }
+                        let limit = cmp::min(chunk_len, len - bytes.len());
+                        let mut chunk = Vec::new();
+                        for slice in &slices {
+                            let n = cmp::min(slice.len(), limit - chunk.len());
+                            chunk.extend_from_slice(&slice[..n]);
+                        }
+                        bytes.extend_from_slice(&chunk);
+                        chunk.len()
+                    };
+                    encoder.consume(n);
+                }
+                bytes
+            };
+
+            let mut encoder = new_encoder();
+            for data in &messages {
+                assert!(encoder.queue_message(data_message(data)).is_ok());
+            }
+            let stream = encode(&mut encoder, usize::MAX);
+            let split = split % (stream.len() + 1);
+
+            // Transfer the first `split` bytes of the stream from an encoder
+            // to a decoder, before taking them apart.
+            let mut encoder = new_encoder();
+            for data in &messages {
+                assert!(encoder.queue_message(data_message(data)).is_ok());
+            }
+            let mut decoder = new_decoder();
+            let written = encode(&mut encoder, split);
+            decoder.push(&written);
+            let mut decoded = Vec::new();
+            while let Some(message) = decoder.next_message().unwrap() {
+                decoded.push(message.get_root::<data::Reader>().unwrap().to_vec());
+            }
+
+            // The rest of the stream follows the buffered inbound bytes.
+            let mut inbound = decoder.into_buffered().unwrap();
+            inbound.extend_from_slice(&stream[split..]);
+            let mut decoder = new_decoder();
+            decoder.push(&inbound);
+            while let Some(message) = decoder.next_message().unwrap() {
+                decoded.push(message.get_root::<data::Reader>().unwrap().to_vec());
+            }
+
+            // The unwritten part of the queued messages follows the written
+            // bytes.
+            let (queued, queued_written) = encoder.into_queued();
+            let mut encoder = new_encoder();
+            for message in queued {
+                assert!(encoder.queue_message(message).is_ok());
+            }
+            let mut output = written;
+            output.extend_from_slice(&encode(&mut encoder, usize::MAX)[queued_written..]);
+
+            TestResult::from_bool(messages == decoded && stream == output)
+        }
+
+        quickcheck(into_parts as fn(Vec<Vec<u8>>, usize, usize, bool) -> TestResult);
+    }
+
+    #[test]
+    fn test_into_parts() {
+        let mut input = Vec::new();
+        serialize::write_message(&mut input, &data_message(b"abcdefgh")).unwrap();
+        serialize::write_message(&mut input, &data_message(b"ijklmnop")).unwrap();
+        let stream = test_utils::BlockingStream::new(Cursor::new(input.clone()), 40);
+        let mut stream = MessageStream::new(stream, message::ReaderOptions::new());
+
+        // The stream blocks part way through the second message.
+        let mut message = None;
+        while let None = message {
+            message = stream.read_message().unwrap();
+        }
+        assert!(stream.read_message().unwrap().is_none());
+        stream.write_message(data_message(b"qrstuvwx")).unwrap();
+
+        let mut parts = stream.into_parts();
+        let mut inbound = parts.inbound.unwrap();
+        let position = parts.inner.inner_mut().position() as usize;
+        inbound.extend_from_slice(&input[position..]);
+        let message = serialize::read_message(&mut Cursor::new(inbound), message::ReaderOptions::new()).unwrap();
+        assert_eq!(b"ijklmnop", message.get_root::<data::Reader>().unwrap());
+        assert_eq!(1, parts.outbound.len());
+        assert_eq!(0, parts.outbound_written);
+    }
+
+    #[test]
+    fn check_with_prefix() {
+        fn with_prefix(messages: Vec<Vec<u8>>, split: usize, packed: bool) -> TestResult {
+            let mut input = Vec::new();
+            for data in &messages {
+                serialize::write_message(&mut input, &data_message(data)).unwrap();
+            }
+            if packed {
+                let mut packed_input = Vec::new();
+                packed::pack(&input, &mut packed_input);
+                input = packed_input;
+            }
+
+            // A handshake has read the first `split` bytes of the stream.
+            let split = split % (input.len() + 1);
+            let stream = Cursor::new(input[split..].to_vec());
+            let message_reader = if packed {
+                MessageReader::new_packed(stream, message::ReaderOptions::new())
+            } else {
+                MessageReader::new(stream, message::ReaderOptions::new())
+            };
+            let mut message_reader = message_reader.with_prefix(&input[..split]);
+
+            let mut read = Vec::new();
+            while let Some(message) = message_reader.read_message().unwrap() {
+                read.push(message.get_root::<data::Reader>().unwrap().to_vec());
+            }
+            TestResult::from_bool(messages == read && message_reader.is_closed())
+        }
+
+        quickcheck(with_prefix as fn(Vec<Vec<u8>>, usize, bool) -> TestResult);
+    }
+
+    #[test]
+    fn test_on_ready() {
+        let mut input = Vec::new();
+        for data in &[b"abcdefgh", b"ijklmnop", b"qrstuvwx"] {
+            serialize::write_message(&mut input, &data_message(*data)).unwrap();
+        }
+        let stream = test_utils::DuplexStream { input: Cursor::new(input), output: Vec::new(), capacity: 0 };
+        let mut options = QueueOptions::new();
+        options.high_watermark(1).low_watermark(0);
+        let mut stream = MessageStream::new(stream, message::ReaderOptions::new()).with_queue_options(options);
+        assert_eq!(Interest { readable: true, writable: false }, stream.interest());
+
+        fn echo(stream: &mut MessageStream<test_utils::DuplexStream>,
+                message: message::Reader<Segments>)
+                -> io::Result<()> {
+            let data = message.get_root::<data::Reader>().unwrap();
+            try!(stream.write_message(data_message(data)));
+            Ok(())
+        }
+
+        // The response can not be written, so reading stops above the high
+        // watermark.
+        let (interest, completed) = stream.on_ready(true, false, echo).unwrap();
+        assert_eq!(Interest { readable: false, writable: true }, interest);
+        assert!(completed.is_empty());
+        assert_eq!(1, stream.outbound_queue_len());
+
+        // Once the response has been written, reading resumes until the stream
+        // would block.
+        stream.inner_mut().capacity = usize::MAX;
+        let (interest, completed) = stream.on_ready(false, true, echo).unwrap();
+        assert_eq!(Interest { readable: true, writable: false }, interest);
+        assert_eq!(3, completed.count());
+        assert!(stream.write().unwrap().is_empty());
+
+        let mut output = Cursor::new(stream.inner().output.clone());
+        for data in &[b"abcdefgh", b"ijklmnop", b"qrstuvwx"] {
+            let message = serialize::read_message(&mut output, message::ReaderOptions::new()).unwrap();
+            assert_eq!(&data[..], message.get_root::<data::Reader>().unwrap());
+        }
+    }
+
+    #[test]
+    fn test_on_ready_skipped_message() {
+        let mut input = Vec::new();
+        for data in &[&b"abcdefgh"[..], &[0; 64][..], &b"ijklmnop"[..]] {
+            serialize::write_message(&mut input, &data_message(data)).unwrap();
+        }
+        let stream = test_utils::DuplexStream { input: Cursor::new(input), output: Vec::new(), capacity: 0 };
+        let mut limits = MessageLimits::new();
+        limits.max_message_size(48).skip_oversized(true);
+        let mut stream: MessageStream<_> =
+            MessageStream::new(stream, message::ReaderOptions::new()).with_message_limits(limits);
+
+        // The oversized message is passed over, and the next message is read.
+        let mut messages = Vec::new();
+        stream.on_ready(true, false, |_, message| {
+            messages.push(message.get_root::<data::Reader>().unwrap().to_vec());
+            Ok(())
+        }).unwrap();
+        assert_eq!(vec![b"abcdefgh".to_vec(), b"ijklmnop".to_vec()], messages);
+    }
+
+    #[test]
+    fn test_on_ready_skipped_traversal_limit() {
+        let mut input = Vec::new();
+        for data in &[&b"abcdefgh"[..], &[0; 64][..], &b"ijklmnop"[..]] {
+            serialize::write_message(&mut input, &data_message(data)).unwrap();
+        }
+        let stream = test_utils::DuplexStream { input: Cursor::new(input), output: Vec::new(), capacity: 0 };
+        let mut options = message::ReaderOptions::new();
+        options.traversal_limit_in_words(4);
+        let mut limits = MessageLimits::new();
+        limits.skip_oversized(true);
+        let mut stream: MessageStream<_> = MessageStream::new(stream, options).with_message_limits(limits);
+
+        // The message which exceeds the traversal limit is passed over, without
+        // poisoning the stream.
+        let mut messages = Vec::new();
+        stream.on_ready(true, false, |_, message| {
+            messages.push(message.get_root::<data::Reader>().unwrap().to_vec());
+            Ok(())
+        }).unwrap();
+        assert_eq!(vec![b"abcdefgh".to_vec(), b"ijklmnop".to_vec()], messages);
+        assert!(!stream.is_read_poisoned());
+    }
+
+    #[test]
+    fn test_on_ready_paused_write() {
+        let mut input = Vec::new();
+        for data in &[b"abcdefgh", b"ijklmnop"] {
+            serialize::write_message(&mut input, &data_message(*data)).unwrap();
+        }
+        let stream = test_utils::DuplexStream { input: Cursor::new(input), output: Vec::new(), capacity: 0 };
+        let mut options = QueueOptions::new();
+        options.high_watermark(1).low_watermark(0);
+        let mut stream = MessageStream::new(stream, message::ReaderOptions::new()).with_queue_options(options);
+
+        fn echo(stream: &mut MessageStream<test_utils::DuplexStream>,
+                message: message::Reader<Segments>)
+                -> io::Result<()> {
+            let data = message.get_root::<data::Reader>().unwrap();
+            try!(stream.write_message(data_message(data)));
+            Ok(())
+        }
+        assert_eq!(Interest { readable: false, writable: true }, stream.on_ready(true, false, echo).unwrap().0);
+
+        // Draining the queue with `write`, rather than `on_ready`, resumes
+        // reading as well.
+        stream.inner_mut().capacity = usize::MAX;
+        stream.write().unwrap();
+        assert_eq!(Interest { readable: true, writable: false }, stream.interest());
+    }
 }
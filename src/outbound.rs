@@ -0,0 +1,118 @@
+//! Messages which may be written to a message stream.
+
+use std::io::{self, Error, ErrorKind};
+use std::rc::Rc;
+use std::result;
+use std::sync::Arc;
+
+use capnp::Word;
+use capnp::message::{Allocator, Builder};
+
+use read::{self, MAX_SEGMENTS};
+
+/// A message which may be queued for writing to a message stream.
+///
+/// The message exposes its segments, and is written with a segment table
+/// followed by the segments, regardless of how the segments are held.
+///
+/// A message read from a stream, `Reader<Segments>`, does not expose its
+/// segments, so it is not itself an `OutboundMessage`. It is re-sent by taking
+/// its `Segments` with `Reader::into_segments`, or `Segments::from`.
+pub trait OutboundMessage {
+    /// Appends the segments of the message to `segments`. A message has at
+    /// least one segment, and the length of each segment is a multiple of 8
+    /// bytes.
+    fn output_segments<'a>(&'a self, segments: &mut Vec<&'a [u8]>);
+}
+
+impl <A> OutboundMessage for Builder<A> where A: Allocator {
+    fn output_segments<'a>(&'a self, segments: &mut Vec<&'a [u8]>) {
+        for segment in self.get_segments_for_output().iter() {
+            segments.push(Word::words_to_bytes(segment));
+        }
+    }
+}
+
+impl <T> OutboundMessage for &T where T: OutboundMessage + ?Sized {
+    fn output_segments<'a>(&'a self, segments: &mut Vec<&'a [u8]>) {
+        (**self).output_segments(segments)
+    }
+}
+
+impl <T> OutboundMessage for &mut T where T: OutboundMessage + ?Sized {
+    fn output_segments<'a>(&'a self, segments: &mut Vec<&'a [u8]>) {
+        (**self).output_segments(segments)
+    }
+}
+
+impl <T> OutboundMessage for Box<T> where T: OutboundMessage + ?Sized {
+    fn output_segments<'a>(&'a self, segments: &mut Vec<&'a [u8]>) {
+        (**self).output_segments(segments)
+    }
+}
+
+impl <T> OutboundMessage for Rc<T> where T: OutboundMessage + ?Sized {
+    fn output_segments<'a>(&'a self, segments: &mut Vec<&'a [u8]>) {
+        (**self).output_segments(segments)
+    }
+}
+
+impl <T> OutboundMessage for Arc<T> where T: OutboundMessage + ?Sized {
+    fn output_segments<'a>(&'a self, segments: &mut Vec<&'a [u8]>) {
+        (**self).output_segments(segments)
+    }
+}
+
+/// An already serialized message, consisting of a segment table followed by
+/// the segments, in the standard stream framing.
+///
+/// The segment table is validated when the message is created, and serialized
+/// again when the message is written.
+pub struct FramedMessage {
+    bytes: Vec<u8>,
+    /// The offset of the first segment.
+    table_len: usize,
+    /// The lengths of the segments, in bytes.
+    segment_lens: Vec<usize>,
+}
+
+impl FramedMessage {
+
+    /// Creates a new `FramedMessage` from the bytes of a single serialized
+    /// message. Returns an error if the bytes are not exactly one message.
+    pub fn new(bytes: Vec<u8>) -> io::Result<FramedMessage> {
+        let mut segment_lens = Vec::new();
+        if try!(read::parse_segment_table(&bytes, MAX_SEGMENTS, &mut segment_lens)) != 0 {
+            return result::Result::Err(Error::new(ErrorKind::InvalidData,
+                                                  "framed message is truncated"));
+        }
+        let table_len = (segment_lens.len() / 2 + 1) * 8;
+        let len = segment_lens.iter().fold(table_len, |acc, &len| acc.saturating_add(len));
+        if len != bytes.len() {
+            return result::Result::Err(Error::new(ErrorKind::InvalidData,
+                                                  "framed message length does not match its \
+                                                   segment table"));
+        }
+        Ok(FramedMessage { bytes: bytes, table_len: table_len, segment_lens: segment_lens })
+    }
+
+    /// Returns the bytes of the message.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// Unwraps the bytes of the message.
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+impl OutboundMessage for FramedMessage {
+    fn output_segments<'a>(&'a self, segments: &mut Vec<&'a [u8]>) {
+        let mut offset = self.table_len;
+        for &len in &self.segment_lens {
+            segments.push(&self.bytes[offset..offset + len]);
+            offset += len;
+        }
+    }
+}
@@ -0,0 +1,470 @@
+//! Incremental packing and unpacking of the Cap'n Proto packed encoding.
+//!
+//! The packed encoding compresses a stream of words by prefixing each word
+//! with a tag byte, in which each set bit signals a nonzero byte of the word.
+//! Only the nonzero bytes follow the tag. A `0x00` tag is followed by a count
+//! of additional zero words, and a `0xff` tag is followed by the eight bytes of
+//! the word, a count of additional words, and then those words verbatim.
+
+use std::{cmp, error, fmt, io};
+
+/// Number of packed bytes requested from the underlying stream at a time.
+const INPUT_SIZE: usize = 4096;
+
+/// Packs the word-aligned bytes in `words` and appends the packed bytes to
+/// `packed`.
+pub fn pack(words: &[u8], packed: &mut Vec<u8>) {
+    assert!(words.len() % 8 == 0);
+
+    let mut offset = 0;
+    while offset < words.len() {
+        let word = &words[offset..offset + 8];
+        offset += 8;
+
+        let tag_offset = packed.len();
+        packed.push(0);
+        let mut tag = 0u8;
+        for (i, &byte) in word.iter().enumerate() {
+            if byte != 0 {
+                tag |= 1 << i;
+                packed.push(byte);
+            }
+        }
+        packed[tag_offset] = tag;
+
+        if tag == 0 {
+            // Count the following zero words.
+            let count = words[offset..].chunks(8)
+                                       .take(255)
+                                       .take_while(|word| word.iter().all(|&byte| byte == 0))
+                                       .count();
+            packed.push(count as u8);
+            offset += count * 8;
+        } else if tag == 0xff {
+            // Count the following words which would not benefit from packing,
+            // and copy them verbatim.
+            let count = words[offset..].chunks(8)
+                                       .take(255)
+                                       .take_while(|word| word.iter().filter(|&&byte| byte == 0).count() < 2)
+                                       .count();
+            packed.push(count as u8);
+            packed.extend_from_slice(&words[offset..offset + count * 8]);
+            offset += count * 8;
+        }
+    }
+}
+
+/// The remainder of a packed stream which could not be packed again, because
+/// the stream is in the middle of a run of verbatim words, and less than a
+/// complete word of the run has been unpacked.
+///
+/// `UnpackedRemainder` is returned wrapped in an `io::Error` of kind
+/// `InvalidData`, and may be taken out with `io::Error::into_inner` and
+/// `downcast`. `bytes` are unpacked, and are followed by the first `verbatim`
+/// bytes of the rest of the stream, which are unpacked as well. The packed
+/// encoding resumes after them.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UnpackedRemainder {
+    /// The unpacked bytes of the partial word.
+    pub bytes: Vec<u8>,
+    /// The number of bytes of the run which remain in the rest of the stream.
+    pub verbatim: usize,
+}
+
+impl fmt::Display for UnpackedRemainder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unable to repack a run of verbatim words: {} bytes unpacked, {} bytes remaining",
+               self.bytes.len(), self.verbatim)
+    }
+}
+
+impl error::Error for UnpackedRemainder {}
+
+/// Incremental unpacker for the packed encoding.
+///
+/// `Unpacker` buffers packed bytes read from the underlying stream, and keeps
+/// track of partially unpacked words and runs, so that unpacking may be resumed
+/// after the stream would block.
+pub struct Unpacker {
+    /// Packed bytes which have been read from the stream.
+    input: Vec<u8>,
+    /// Offset of the first packed byte which has not been consumed.
+    input_offset: usize,
+    /// The most recently unpacked word.
+    word: [u8; 8],
+    /// Offset of the first byte of `word` which has not been unpacked.
+    word_offset: usize,
+    /// Number of zero bytes remaining in the current run of zero words.
+    zeros: usize,
+    /// Number of bytes remaining in the current run of verbatim words.
+    raw: usize,
+}
+
+impl Unpacker {
+
+    pub fn new() -> Unpacker {
+        Unpacker {
+            input: Vec::new(),
+            input_offset: 0,
+            word: [0; 8],
+            word_offset: 8,
+            zeros: 0,
+            raw: 0,
+        }
+    }
+
+    /// Returns an `io::Read` which unpacks the packed bytes read from `inner`.
+    pub fn reader<'a, R>(&'a mut self, inner: &'a mut R) -> UnpackRead<'a, R> where R: io::Read {
+        UnpackRead { unpacker: self, inner: inner }
+    }
+
+    /// Returns `true` if the unpacker holds no packed input or partially
+    /// unpacked words.
+    pub fn is_empty(&self) -> bool {
+        self.input_offset == self.input.len() && self.word_offset == 8 && self.zeros == 0 && self.raw == 0
+    }
+
+    /// Appends packed bytes to the input.
+    pub fn push(&mut self, packed: &[u8]) {
+        self.input.drain(..self.input_offset);
+        self.input_offset = 0;
+        self.input.extend_from_slice(packed);
+    }
+
+    /// Unpacks as many bytes as are available into `out`, and returns the
+    /// number of bytes unpacked.
+    pub fn unpack(&mut self, out: &mut [u8]) -> usize {
+        let mut written = 0;
+        while written < out.len() {
+            let out = &mut out[written..];
+            if self.word_offset < 8 {
+                let n = cmp::min(8 - self.word_offset, out.len());
+                out[..n].copy_from_slice(&self.word[self.word_offset..self.word_offset + n]);
+                self.word_offset += n;
+                written += n;
+            } else if self.zeros > 0 {
+                let n = cmp::min(self.zeros, out.len());
+                for byte in &mut out[..n] {
+                    *byte = 0;
+                }
+                self.zeros -= n;
+                written += n;
+            } else if self.raw > 0 {
+                let input = &self.input[self.input_offset..];
+                if input.is_empty() {
+                    break;
+                }
+                let n = cmp::min(cmp::min(self.raw, out.len()), input.len());
+                out[..n].copy_from_slice(&input[..n]);
+                self.input_offset += n;
+                self.raw -= n;
+                written += n;
+            } else if !self.unpack_word() {
+                break;
+            }
+        }
+        written
+    }
+
+    /// Unpacks the next tagged word from the input. Returns `false` if the
+    /// input does not yet hold the complete tagged word.
+    fn unpack_word(&mut self) -> bool {
+        let input = &self.input[self.input_offset..];
+        let tag = match input.first() {
+            Some(&tag) => tag,
+            None => return false,
+        };
+        let has_count = tag == 0 || tag == 0xff;
+        let len = 1 + tag.count_ones() as usize + has_count as usize;
+        if input.len() < len {
+            return false;
+        }
+
+        let mut offset = 1;
+        for i in 0..8 {
+            if tag & (1 << i) != 0 {
+                self.word[i] = input[offset];
+                offset += 1;
+            } else {
+                self.word[i] = 0;
+            }
+        }
+        match tag {
+            0 => self.zeros = input[offset] as usize * 8,
+            0xff => self.raw = input[offset] as usize * 8,
+            _ => (),
+        }
+
+        self.input_offset += len;
+        self.word_offset = 0;
+        true
+    }
+
+    /// Unpacks the remaining input onto `unpacked`, which holds the unpacked
+    /// bytes since a word boundary, and packs the unpacked bytes again,
+    /// followed by the packed input which could not be unpacked yet. Unpacking
+    /// the result yields `unpacked` and then the rest of the stream.
+    ///
+    /// Fails if the unpacker is in the middle of a run of verbatim words, and
+    /// `unpacked` does not hold a complete word to begin a new run with. The
+    /// error then holds the unpacked bytes as an `UnpackedRemainder`.
+    pub fn into_packed(mut self, mut unpacked: Vec<u8>) -> io::Result<Vec<u8>> {
+        loop {
+            let len = unpacked.len();
+            unpacked.resize(len + INPUT_SIZE, 0);
+            let n = self.unpack(&mut unpacked[len..]);
+            unpacked.truncate(len + n);
+            if n == 0 {
+                break;
+            }
+        }
+
+        let mut packed = Vec::new();
+        if self.raw == 0 {
+            // Only complete words have been unpacked.
+            pack(&unpacked, &mut packed);
+            packed.extend_from_slice(&self.input[self.input_offset..]);
+            return Ok(packed);
+        }
+
+        // The rest of the stream continues the run of verbatim words, so the
+        // last complete word heads a new run which includes the partial word
+        // and the remaining words.
+        let aligned = unpacked.len() - unpacked.len() % 8;
+        if aligned < 8 {
+            let remainder = UnpackedRemainder { bytes: unpacked, verbatim: self.raw };
+            return Err(io::Error::new(io::ErrorKind::InvalidData, remainder));
+        }
+        pack(&unpacked[..aligned - 8], &mut packed);
+        packed.push(0xff);
+        packed.extend_from_slice(&unpacked[aligned - 8..aligned]);
+        packed.push(((unpacked.len() - aligned + self.raw) / 8) as u8);
+        packed.extend_from_slice(&unpacked[aligned..]);
+        Ok(packed)
+    }
+
+    /// Reads more packed bytes from `read`. Returns `false` if the stream is
+    /// at EOF.
+    fn refill<R>(&mut self, read: &mut R) -> io::Result<bool> where R: io::Read {
+        self.input.drain(..self.input_offset);
+        self.input_offset = 0;
+
+        let len = self.input.len();
+        self.input.resize(len + INPUT_SIZE, 0);
+        let result = read.read(&mut self.input[len..]);
+        self.input.truncate(len + *result.as_ref().unwrap_or(&0));
+        result.map(|n| n > 0)
+    }
+}
+
+/// An `io::Read` which unpacks a packed stream.
+pub struct UnpackRead<'a, R: 'a> {
+    unpacker: &'a mut Unpacker,
+    inner: &'a mut R,
+}
+
+impl <'a, R> io::Read for UnpackRead<'a, R> where R: io::Read {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            match self.unpacker.unpack(buf) {
+                0 => if !try!(self.unpacker.refill(self.inner)) { return Ok(0) },
+                n => return Ok(n),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use std::io::{self, Cursor, Read};
+
+    use super::{pack, UnpackedRemainder, Unpacker};
+
+    use test_utils;
+
+    use quickcheck::{quickcheck, TestResult};
+
+    fn packs_to(unpacked: &[u8], expected: &[u8]) {
+        let mut packed = Vec::new();
+        pack(unpacked, &mut packed);
+        assert_eq!(expected, &*packed);
+
+        let mut unpacker = Unpacker::new();
+        let mut actual = Vec::new();
+        unpacker.reader(&mut Cursor::new(expected)).read_to_end(&mut actual).unwrap();
+        assert_eq!(unpacked, &*actual);
+    }
+
+    #[test]
+    fn test_pack() {
+        packs_to(&[], &[]);
+        packs_to(&[0; 8], &[0, 0]);
+        packs_to(&[0; 24], &[0, 2]);
+        packs_to(&[0, 0, 12, 0, 0, 34, 0, 0], &[0x24, 12, 34]);
+        packs_to(&[1, 3, 2, 4, 5, 7, 6, 8], &[0xff, 1, 3, 2, 4, 5, 7, 6, 8, 0]);
+        packs_to(&[1, 3, 2, 4, 5, 7, 6, 8, 8, 6, 7, 4, 5, 2, 3, 1],
+                 &[0xff, 1, 3, 2, 4, 5, 7, 6, 8, 1, 8, 6, 7, 4, 5, 2, 3, 1]);
+        packs_to(&[1, 3, 2, 4, 5, 7, 6, 8, 0, 0, 0, 0, 0, 0, 0, 0],
+                 &[0xff, 1, 3, 2, 4, 5, 7, 6, 8, 0, 0, 0]);
+    }
+
+    /// Checks packings taken from the Cap'n Proto encoding specification and
+    /// the reference implementation's tests.
+    #[test]
+    fn test_pack_canonical() {
+        // The example from the encoding specification.
+        packs_to(&[0x08, 0, 0, 0, 0x03, 0, 0x02, 0, 0x19, 0, 0, 0, 0xaa, 0x01, 0, 0],
+                 &[0x51, 0x08, 0x03, 0x02, 0x31, 0x19, 0xaa, 0x01]);
+
+        // Tagged words.
+        packs_to(&[8, 0, 100, 6, 0, 1, 1, 2], &[0xed, 8, 100, 6, 1, 1, 2]);
+        packs_to(&[0, 0, 1, 0, 2, 0, 3, 1], &[0xd4, 1, 2, 3, 1]);
+
+        // Runs of zero words.
+        packs_to(&[0; 16], &[0, 1]);
+        packs_to(&[0; 8 * 256], &[0, 255]);
+        packs_to(&[0; 8 * 257], &[0, 255, 0, 0]);
+        packs_to(&[0, 0, 0, 0, 0, 0, 0, 0, 1, 3, 2, 4, 5, 7, 6, 8],
+                 &[0, 0, 0xff, 1, 3, 2, 4, 5, 7, 6, 8, 0]);
+        packs_to(&[0, 0, 12, 0, 0, 34, 0, 0, 1, 3, 2, 4, 5, 7, 6, 8],
+                 &[0x24, 12, 34, 0xff, 1, 3, 2, 4, 5, 7, 6, 8, 0]);
+        packs_to(&[8, 0, 100, 6, 0, 1, 1, 2,
+                   0, 0, 0, 0, 0, 0, 0, 0,
+                   0, 0, 0, 0, 0, 0, 0, 0,
+                   0, 0, 0, 0, 0, 0, 0, 0,
+                   0, 0, 1, 0, 2, 0, 3, 1],
+                 &[0xed, 8, 100, 6, 1, 1, 2, 0, 2, 0xd4, 1, 2, 3, 1]);
+
+        // Runs of verbatim words, which include words with a single zero byte
+        // and end at a word with two or more zero bytes.
+        packs_to(&[1, 2, 3, 4, 5, 6, 7, 8,
+                   1, 2, 3, 4, 5, 6, 7, 8,
+                   1, 2, 3, 4, 5, 6, 7, 8,
+                   1, 2, 3, 4, 5, 6, 7, 8,
+                   0, 2, 4, 0, 9, 0, 5, 1],
+                 &[0xff, 1, 2, 3, 4, 5, 6, 7, 8, 3,
+                   1, 2, 3, 4, 5, 6, 7, 8,
+                   1, 2, 3, 4, 5, 6, 7, 8,
+                   1, 2, 3, 4, 5, 6, 7, 8,
+                   0xd6, 2, 4, 9, 5, 1]);
+        packs_to(&[1, 2, 3, 4, 5, 6, 7, 8,
+                   1, 2, 3, 4, 5, 6, 7, 8,
+                   6, 2, 4, 3, 9, 0, 5, 1,
+                   1, 2, 3, 4, 5, 6, 7, 8,
+                   0, 2, 4, 0, 9, 0, 5, 1],
+                 &[0xff, 1, 2, 3, 4, 5, 6, 7, 8, 3,
+                   1, 2, 3, 4, 5, 6, 7, 8,
+                   6, 2, 4, 3, 9, 0, 5, 1,
+                   1, 2, 3, 4, 5, 6, 7, 8,
+                   0xd6, 2, 4, 9, 5, 1]);
+
+        // A run of verbatim words holds at most 255 words after the tagged word.
+        let unpacked = [1; 8 * 257];
+        let mut packed = vec![0xff, 1, 1, 1, 1, 1, 1, 1, 1, 255];
+        packed.extend_from_slice(&[1; 8 * 255]);
+        packed.extend_from_slice(&[0xff, 1, 1, 1, 1, 1, 1, 1, 1, 0]);
+        packs_to(&unpacked, &packed);
+    }
+
+    #[test]
+    fn test_into_packed_partial_run() {
+        let packed = [0xff, 1, 2, 3, 4, 5, 6, 7, 8, 1, 9, 10, 11, 12, 13, 14, 15, 16];
+        let mut unpacker = Unpacker::new();
+        unpacker.push(&packed[..13]);
+        let mut out = [0; 16];
+        assert_eq!(11, unpacker.unpack(&mut out));
+
+        // Only three bytes of the second word of the run follow the boundary.
+        let error = unpacker.into_packed(out[8..11].to_vec()).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, error.kind());
+        let remainder = error.into_inner().unwrap().downcast::<UnpackedRemainder>().unwrap();
+        assert_eq!(UnpackedRemainder { bytes: vec![9, 10, 11], verbatim: 5 }, *remainder);
+    }
+
+    #[test]
+    fn check_into_packed() {
+        fn into_packed(words: Vec<u64>, split: usize, input_len: usize) -> TestResult {
+            let mut unpacked = Vec::new();
+            for word in words {
+                for i in 0..8 {
+                    let byte = (word >> (i * 8)) as u8;
+                    unpacked.push(if byte % 5 == 0 { 0 } else { byte });
+                }
+            }
+            let mut packed = Vec::new();
+            pack(&unpacked, &mut packed);
+            let input_len = input_len % (packed.len() + 1);
+
+            // Partially unpack the input, and repack everything unpacked after
+            // a word boundary.
+            let mut unpacker = Unpacker::new();
+            unpacker.push(&packed[..input_len]);
+            let mut out = vec![0; unpacked.len()];
+            let n = unpacker.unpack(&mut out);
+            let split = (split % (n / 8 + 1)) * 8;
+
+            let repacked = match unpacker.into_packed(out[split..n].to_vec()) {
+                Ok(mut repacked) => {
+                    repacked.extend_from_slice(&packed[input_len..]);
+                    repacked
+                },
+                Err(error) => {
+                    // The rest of the run completes the unpacked partial word.
+                    let remainder = *error.into_inner().unwrap().downcast::<UnpackedRemainder>().unwrap();
+                    let rest = &packed[input_len..];
+                    let mut words = remainder.bytes;
+                    words.extend_from_slice(&rest[..remainder.verbatim]);
+                    let mut repacked = Vec::new();
+                    pack(&words, &mut repacked);
+                    repacked.extend_from_slice(&rest[remainder.verbatim..]);
+                    repacked
+                },
+            };
+
+            let mut actual = Vec::new();
+            Unpacker::new().reader(&mut Cursor::new(repacked)).read_to_end(&mut actual).unwrap();
+            TestResult::from_bool(&unpacked[split..] == &*actual)
+        }
+
+        quickcheck(into_packed as fn(Vec<u64>, usize, usize) -> TestResult);
+    }
+
+    #[test]
+    fn check_pack_unpack_nonblock() {
+        fn pack_unpack(words: Vec<u64>, frequency: usize) -> TestResult {
+            if frequency == 0 { return TestResult::discard(); }
+            // Skew the words towards zero bytes, so that all tags are exercised.
+            let mut unpacked = Vec::new();
+            for word in words {
+                for i in 0..8 {
+                    let byte = (word >> (i * 8)) as u8;
+                    unpacked.push(if byte % 3 == 0 { 0 } else { byte });
+                }
+            }
+
+            let mut packed = Vec::new();
+            pack(&unpacked, &mut packed);
+
+            let mut stream = test_utils::BlockingStream::new(Cursor::new(packed), frequency);
+            let mut unpacker = Unpacker::new();
+            let mut actual = Vec::new();
+            let mut buf = [0; 13];
+            loop {
+                match unpacker.reader(&mut stream).read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(n) => actual.extend_from_slice(&buf[..n]),
+                    Err(ref error) if error.kind() == io::ErrorKind::WouldBlock => continue,
+                    Err(error) => panic!("{}", error),
+                }
+            }
+
+            TestResult::from_bool(unpacked == actual)
+        }
+
+        quickcheck(pack_unpack as fn(Vec<u64>, usize) -> TestResult);
+    }
+}
@@ -0,0 +1,43 @@
+//! Tracking of fatal stream errors.
+
+use std::io;
+
+/// Records the first fatal error of one half of a message stream, after which
+/// the half is poisoned, and every further operation fails.
+pub struct Poison {
+    /// The kind and description of the fatal error, if one has occurred.
+    error: Option<(io::ErrorKind, String)>,
+}
+
+impl Poison {
+
+    pub fn new() -> Poison {
+        Poison { error: None }
+    }
+
+    pub fn is_poisoned(&self) -> bool {
+        self.error.is_some()
+    }
+
+    /// Returns an error describing the fatal error, if one has occurred.
+    pub fn check(&self) -> io::Result<()> {
+        match self.error {
+            Some((kind, ref description)) => {
+                Err(io::Error::new(kind, format!("stream is poisoned by a previous error: {}",
+                                                 description)))
+            },
+            None => Ok(()),
+        }
+    }
+
+    /// Records the error of a failed result as fatal, and passes the result
+    /// through.
+    pub fn record<T>(&mut self, result: io::Result<T>) -> io::Result<T> {
+        if let Err(ref error) = result {
+            if self.error.is_none() {
+                self.error = Some((error.kind(), error.to_string()));
+            }
+        }
+        result
+    }
+}
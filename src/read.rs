@@ -0,0 +1,741 @@
+//! Reading Cap'n Proto messages from a stream.
+
+use std::{cmp, error, fmt, mem, usize};
+use std::io::{self, Error, ErrorKind, Result};
+use std::result;
+
+use byteorder::{ByteOrder, LittleEndian};
+use capnp::Word;
+use capnp::message::{
+    Reader,
+    ReaderOptions,
+    ReaderSegments,
+};
+
+use buf::{BufferOptions, BufferPool, MutBuf, Buf};
+use outbound::OutboundMessage;
+use packed::Unpacker;
+use poison::Poison;
+
+/// Default maximum number of segments in a message.
+pub const MAX_SEGMENTS: usize = 511;
+
+/// Limits on the framing of inbound messages, which are checked when a
+/// message's segment table is read, before any of its segments are buffered.
+///
+/// The limits are independent of the traversal limit of the `ReaderOptions`,
+/// which is checked as well. Violations, including of the traversal limit, are
+/// reported as `InvalidData` errors which wrap a `LimitError`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MessageLimits {
+    /// The maximum number of segments in a message. Defaults to 511.
+    pub max_segments: usize,
+
+    /// The maximum length of a single segment, in bytes. Defaults to
+    /// unlimited.
+    pub max_segment_size: usize,
+
+    /// The maximum length of a message, including its segment table, in bytes.
+    /// Defaults to unlimited.
+    pub max_message_size: usize,
+
+    /// Whether messages which exceed `max_segment_size`, `max_message_size`,
+    /// or the traversal limit of the `ReaderOptions` are skipped, instead of
+    /// failing the stream. The bytes of a skipped
+    /// message are discarded as they are read, without being buffered in full.
+    /// Defaults to `false`.
+    pub skip_oversized: bool,
+}
+
+impl Default for MessageLimits {
+    fn default() -> MessageLimits {
+        MessageLimits {
+            max_segments: MAX_SEGMENTS,
+            max_segment_size: usize::MAX,
+            max_message_size: usize::MAX,
+            skip_oversized: false,
+        }
+    }
+}
+
+impl MessageLimits {
+    pub fn new() -> MessageLimits {
+        MessageLimits::default()
+    }
+
+    pub fn max_segments(&mut self, value: usize) -> &mut MessageLimits {
+        self.max_segments = value;
+        self
+    }
+
+    pub fn max_segment_size(&mut self, value: usize) -> &mut MessageLimits {
+        self.max_segment_size = value;
+        self
+    }
+
+    pub fn max_message_size(&mut self, value: usize) -> &mut MessageLimits {
+        self.max_message_size = value;
+        self
+    }
+
+    pub fn skip_oversized(&mut self, value: bool) -> &mut MessageLimits {
+        self.skip_oversized = value;
+        self
+    }
+}
+
+/// A violation of the `MessageLimits` by an inbound message.
+///
+/// `LimitError` is returned wrapped in an `io::Error` of kind `InvalidData`,
+/// and may be retrieved with `io::Error::get_ref` and `downcast_ref`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LimitError {
+    /// The message has more segments than the limit.
+    TooManySegments { count: usize, limit: usize },
+    /// A segment of the message is longer than the limit, in bytes.
+    SegmentTooLarge { len: usize, limit: usize },
+    /// The message is longer than the limit, in bytes.
+    MessageTooLarge { len: usize, limit: usize },
+    /// The segments of the message are longer than the traversal limit of the
+    /// `ReaderOptions`, in bytes.
+    TraversalLimitExceeded { len: usize, limit: usize },
+}
+
+impl fmt::Display for LimitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LimitError::TooManySegments { count, limit } => {
+                write!(f, "too many segments in Cap'n Proto message: {} (limit {})", count, limit)
+            },
+            LimitError::SegmentTooLarge { len, limit } => {
+                write!(f, "Cap'n Proto segment is too large: {} bytes (limit {})", len, limit)
+            },
+            LimitError::MessageTooLarge { len, limit } => {
+                write!(f, "Cap'n Proto message is too large: {} bytes (limit {})", len, limit)
+            },
+            LimitError::TraversalLimitExceeded { len, limit } => {
+                write!(f, "Cap'n Proto message exceeds the traversal limit: {} bytes (limit {})", len, limit)
+            },
+        }
+    }
+}
+
+impl error::Error for LimitError {}
+
+impl From<LimitError> for io::Error {
+    fn from(error: LimitError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, error)
+    }
+}
+
+/// A message which was skipped because it exceeded the message limits, when
+/// `MessageLimits::skip_oversized` is set.
+///
+/// `SkippedMessage` is returned wrapped in an `io::Error` of kind
+/// `InvalidData`, and may be retrieved with `io::Error::get_ref` and
+/// `downcast_ref`. Unlike other errors, it does not poison the stream, and the
+/// next message may be read.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SkippedMessage {
+    /// The length of the skipped message, including its segment table, in
+    /// bytes.
+    pub len: usize,
+    /// The limit which the message exceeded.
+    pub cause: LimitError,
+}
+
+impl fmt::Display for SkippedMessage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "skipped Cap'n Proto message of {} bytes: {}", self.len, self.cause)
+    }
+}
+
+impl error::Error for SkippedMessage {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        Some(&self.cause)
+    }
+}
+
+/// The segment table of an inbound message, which may be inspected before the
+/// message's segments have been read.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MessageHeader {
+    /// The lengths of the segments, in bytes.
+    segment_lens: Vec<usize>,
+}
+
+impl MessageHeader {
+
+    /// Returns the number of segments in the message.
+    pub fn segment_count(&self) -> usize {
+        self.segment_lens.len()
+    }
+
+    /// Returns the lengths of the segments, in bytes.
+    pub fn segment_lens(&self) -> &[usize] {
+        &self.segment_lens
+    }
+
+    /// Returns the length of the message, including its segment table, in
+    /// bytes.
+    pub fn message_len(&self) -> usize {
+        self.segment_lens.iter().fold((self.segment_lens.len() / 2 + 1) * 8, |acc, &len| acc + len)
+    }
+}
+
+/// A Cap'n Proto message container.
+///
+/// The segments of a message read from a stream may be queued on another
+/// stream as an `OutboundMessage`, without copying them. The segments of a
+/// `Reader<Segments>` are taken with `into_segments`, or by converting the
+/// reader with `From`. The loaned read buffers remain alive until the queued
+/// message has been written and dropped.
+pub struct Segments {
+    segments: Vec<Buf>,
+}
+
+impl ReaderSegments for Segments {
+    fn get_segment(&self, id: u32) -> Option<&[Word]> {
+        self.segments.get(id as usize).map(|buf| Word::bytes_to_words(&*buf))
+    }
+}
+
+impl OutboundMessage for Segments {
+    fn output_segments<'a>(&'a self, segments: &mut Vec<&'a [u8]>) {
+        segments.extend(self.segments.iter().map(|buf| &**buf));
+    }
+}
+
+impl From<Reader<Segments>> for Segments {
+    fn from(reader: Reader<Segments>) -> Segments {
+        reader.into_segments()
+    }
+}
+
+/// The read half of the message stream protocol, decoupled from IO.
+///
+/// Bytes received from the stream are pushed into the decoder with `push`, or
+/// read directly from an `io::Read` with `read_from`, and complete messages are
+/// taken out with `next_message`.
+pub struct Decoder {
+    options: ReaderOptions,
+
+    /// The current read buffer.
+    buf: MutBuf,
+    /// The current read offset.
+    buf_offset: usize,
+    /// The segment sizes of the remaining segments of message currently being
+    /// read, in reverse order.
+    remaining_segments: Vec<usize>,
+    /// The segments of the message currently being read.
+    segments: Vec<Buf>,
+    /// The number of bytes after the read offset which must be buffered
+    /// before the current message can make progress.
+    needed: usize,
+    /// The unpacker, if the stream is packed.
+    unpacker: Option<Unpacker>,
+    /// Whether the stream has reached EOF.
+    eof: bool,
+    /// Records fatal errors.
+    poison: Poison,
+    /// Limits on the framing of inbound messages.
+    limits: MessageLimits,
+    /// The number of bytes of a skipped message which remain to be discarded.
+    skip: Option<u64>,
+
+    /// Options controlling the sizes of read buffers.
+    buffer_options: BufferOptions,
+    /// Moving average of the lengths of recently read messages.
+    average_message_len: usize,
+}
+
+impl Decoder {
+
+    /// Creates a new decoder for unpacked messages.
+    pub fn new(options: ReaderOptions) -> Decoder {
+        Decoder::with_packing(options, false)
+    }
+
+    /// Creates a new decoder for messages in the packed encoding.
+    pub fn new_packed(options: ReaderOptions) -> Decoder {
+        Decoder::with_packing(options, true)
+    }
+
+    fn with_packing(options: ReaderOptions, packed: bool) -> Decoder {
+        Decoder {
+            options: options,
+            buf: MutBuf::with_capacity(BufferOptions::default().initial_size),
+            buf_offset: 0,
+            remaining_segments: Vec::new(),
+            segments: Vec::new(),
+            needed: 8,
+            unpacker: if packed { Some(Unpacker::new()) } else { None },
+            eof: false,
+            poison: Poison::new(),
+            limits: MessageLimits::default(),
+            skip: None,
+            buffer_options: BufferOptions::default(),
+            average_message_len: 0,
+        }
+    }
+
+    /// Sets the buffer options. If no bytes have been buffered yet, the read
+    /// buffer is replaced with one of the initial size.
+    pub fn set_buffer_options(&mut self, options: BufferOptions) {
+        self.buffer_options = options;
+        let pool = self.buf.pool();
+        self.reset_buf(pool);
+    }
+
+    /// Sets the limits on the framing of inbound messages.
+    pub fn set_message_limits(&mut self, limits: MessageLimits) {
+        self.limits = limits;
+    }
+
+    /// Sets the pool from which read buffers are taken. If no bytes have been
+    /// buffered yet, the read buffer is replaced with one from the pool.
+    pub fn set_buffer_pool(&mut self, pool: BufferPool) {
+        self.reset_buf(Some(pool));
+    }
+
+    /// Replaces the read buffer if no bytes have been buffered yet.
+    fn reset_buf(&mut self, pool: Option<BufferPool>) {
+        if self.buf.is_empty() {
+            self.buf = MutBuf::with_capacity_in(self.buffer_options.initial_size, pool);
+        }
+    }
+
+    /// Returns `true` if a previous error has poisoned the decoder.
+    pub fn is_poisoned(&self) -> bool {
+        self.poison.is_poisoned()
+    }
+
+    /// Returns `true` if the decoder expects messages in the packed encoding.
+    pub fn is_packed(&self) -> bool {
+        self.unpacker.is_some()
+    }
+
+    /// Returns the number of buffered bytes which have not yet been taken out
+    /// as part of a message.
+    fn buffered_len(&self) -> usize {
+        self.buf.len() - self.buf_offset
+    }
+
+    /// Ensures that the read buffer has capacity for at least `amount` bytes
+    /// after the read offset.
+    fn reserve(&mut self, amount: usize) {
+        let capacity = self.buffer_options.replacement_size(amount, self.average_message_len);
+        self.buf.reserve(&mut self.buf_offset, amount, capacity);
+    }
+
+    /// Returns the amount of bytes which the read buffer should have capacity
+    /// for before more bytes are added to it. This is always more than the
+    /// number of buffered bytes, so that the buffer has spare capacity.
+    fn reserve_amount(&self) -> usize {
+        cmp::max(self.needed, self.buffered_len() + 1)
+    }
+
+    /// Pushes bytes received from the stream into the decoder. The bytes are
+    /// unpacked if the decoder is packed.
+    pub fn push(&mut self, bytes: &[u8]) {
+        if self.unpacker.is_none() {
+            let amount = self.buffered_len() + bytes.len();
+            self.reserve(amount);
+            let n = bytes.len();
+            self.buf.spare_mut()[..n].copy_from_slice(bytes);
+            self.buf.commit(n);
+            return;
+        }
+
+        self.unpacker.as_mut().unwrap().push(bytes);
+        let mut amount = self.reserve_amount();
+        loop {
+            self.reserve(amount);
+            let filled = {
+                let Decoder { ref mut buf, ref mut unpacker, .. } = *self;
+                let (n, spare_len) = {
+                    let spare = buf.spare_mut();
+                    (unpacker.as_mut().unwrap().unpack(spare), spare.len())
+                };
+                buf.commit(n);
+                n == spare_len
+            };
+            if !filled {
+                break;
+            }
+            // The packed bytes may unpack to many times their length, so grow
+            // the buffer geometrically until they have all been unpacked.
+            amount = cmp::max(self.reserve_amount(), self.buffered_len() * 2);
+        }
+    }
+
+    /// Signals that the stream has reached EOF, so that no more bytes will be
+    /// pushed into the decoder. Once the buffered messages have been taken out,
+    /// `next_message` returns an `UnexpectedEof` error if the stream ended in
+    /// the middle of a message.
+    pub fn push_eof(&mut self) {
+        self.eof = true;
+    }
+
+    /// Returns `true` if no partial message is buffered.
+    fn is_at_message_boundary(&self) -> bool {
+        self.buffered_len() == 0
+            && self.remaining_segments.is_empty()
+            && self.skip.is_none()
+            && self.unpacker.as_ref().is_none_or(Unpacker::is_empty)
+    }
+
+    /// Returns `true` if the stream has reached EOF at a message boundary, and
+    /// all messages have been taken out of the decoder.
+    pub fn is_closed(&self) -> bool {
+        self.eof && self.is_at_message_boundary()
+    }
+
+    /// Reads bytes from `read` into the decoder with a single call to `read`,
+    /// unpacking them if the decoder is packed. Returns the number of bytes
+    /// added to the decoder, or 0 if the stream is at EOF.
+    pub fn read_from<R>(&mut self, read: &mut R) -> io::Result<usize> where R: io::Read {
+        let amount = self.reserve_amount();
+        self.reserve(amount);
+        let Decoder { ref mut buf, ref mut unpacker, .. } = *self;
+        match *unpacker {
+            Some(ref mut unpacker) => buf.read_from(&mut unpacker.reader(read)),
+            None => buf.read_from(read),
+        }
+    }
+
+    /// Parses the segment table from the buffered bytes, populating the
+    /// `remaining_segments` field of the decoder on success. Returns `false` if
+    /// the segment table has not been completely buffered.
+    fn parse_segment_table(&mut self) -> Result<bool> {
+        assert!(self.remaining_segments.is_empty());
+        match try!(parse_segment_table(&self.buf[self.buf_offset..],
+                                       self.limits.max_segments,
+                                       &mut self.remaining_segments)) {
+            0 => (),
+            n => {
+                self.needed = n;
+                return Ok(false);
+            },
+        }
+
+        let table_len = (self.remaining_segments.len() / 2 + 1) * 8;
+        self.buf_offset += table_len;
+
+        let limits = self.limits;
+        let segments_len = self.remaining_segments
+                               .iter()
+                               .try_fold(0u64, |n, &len| n.checked_add(len as u64));
+        let message_len = segments_len.and_then(|len| len.checked_add(table_len as u64));
+
+        let traversal_limit = self.options.traversal_limit_in_words.saturating_mul(8);
+        let violation = match self.remaining_segments.iter().find(|&&len| len > limits.max_segment_size) {
+            Some(&len) => Some(LimitError::SegmentTooLarge { len: len, limit: limits.max_segment_size }),
+            None => match (message_len, segments_len) {
+                (Some(len), _) if len > limits.max_message_size as u64 => {
+                    Some(LimitError::MessageTooLarge { len: saturate(len), limit: limits.max_message_size })
+                },
+                (None, _) => Some(LimitError::MessageTooLarge { len: usize::MAX, limit: limits.max_message_size }),
+                (_, Some(len)) if len > traversal_limit => {
+                    Some(LimitError::TraversalLimitExceeded { len: saturate(len), limit: saturate(traversal_limit) })
+                },
+                _ => None,
+            },
+        };
+
+        if let Some(cause) = violation {
+            match (segments_len, message_len) {
+                (Some(skip), Some(len)) if limits.skip_oversized && len <= usize::MAX as u64 => {
+                    // The segment table has been consumed, so only the
+                    // segments remain to be discarded.
+                    self.remaining_segments.clear();
+                    self.skip = Some(skip);
+                    let skipped = SkippedMessage { len: len as usize, cause: cause };
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, skipped));
+                },
+                _ => return Err(From::from(cause)),
+            }
+        }
+
+        self.remaining_segments.reverse();
+        Ok(true)
+    }
+
+    /// Returns the next message from the buffered bytes, or `None` if the
+    /// entire message has not yet been buffered, or if the stream has been
+    /// closed at a message boundary.
+    ///
+    /// If the stream has reached EOF in the middle of a message, an
+    /// `UnexpectedEof` error is returned.
+    ///
+    /// If an error is returned, the decoder is poisoned, and every further
+    /// call fails, unless the error reports a `SkippedMessage`.
+    pub fn next_message(&mut self) -> Result<Option<Reader<Segments>>> {
+        try!(self.poison.check());
+        let result = self.take_message();
+        self.record(result)
+    }
+
+    /// Records the error of a failed result as fatal, unless it reports a
+    /// skipped message, and passes the result through.
+    fn record<T>(&mut self, result: Result<T>) -> Result<T> {
+        let skipped = match result {
+            Err(ref error) => error.get_ref().is_some_and(|error| error.is::<SkippedMessage>()),
+            Ok(_) => false,
+        };
+        if skipped { result } else { self.poison.record(result) }
+    }
+
+    /// Returns the next message from the buffered bytes, without checking or
+    /// poisoning the decoder.
+    fn take_message(&mut self) -> Result<Option<Reader<Segments>>> {
+        let message = try!(self.decode());
+        self.check_eof(message)
+    }
+
+    /// Returns an `UnexpectedEof` error if nothing could be decoded, and the
+    /// stream has reached EOF in the middle of a message.
+    fn check_eof<T>(&self, decoded: Option<T>) -> Result<Option<T>> {
+        match decoded {
+            None if self.eof && !self.is_at_message_boundary() => {
+                Err(Error::new(ErrorKind::UnexpectedEof, "stream closed in the middle of a message"))
+            },
+            decoded => Ok(decoded),
+        }
+    }
+
+    /// Returns the segment table of the next message, once it has been
+    /// buffered, without taking the message out of the decoder. Returns `None`
+    /// if the segment table has not yet been buffered, or if the stream has
+    /// been closed at a message boundary.
+    ///
+    /// The message limits are checked when the segment table is parsed, so
+    /// errors are returned and recorded as by `next_message`.
+    pub fn peek_header(&mut self) -> Result<Option<MessageHeader>> {
+        try!(self.poison.check());
+        let result = self.take_header();
+        self.record(result)
+    }
+
+    /// Returns the segment table of the next message, without checking or
+    /// poisoning the decoder.
+    fn take_header(&mut self) -> Result<Option<MessageHeader>> {
+        let header = if try!(self.begin_message()) {
+            let segment_lens = self.segments.iter()
+                                            .map(|segment| segment.len())
+                                            .chain(self.remaining_segments.iter().rev().cloned())
+                                            .collect();
+            Some(MessageHeader { segment_lens: segment_lens })
+        } else {
+            None
+        };
+        self.check_eof(header)
+    }
+
+    /// Discards the bytes of a skipped message, and parses the segment table of
+    /// the next message if it has not been parsed yet. Returns `false` if more
+    /// bytes must be buffered first.
+    fn begin_message(&mut self) -> Result<bool> {
+        if let Some(skip) = self.skip {
+            // Discard the buffered bytes of the skipped message. Only a single
+            // byte is needed to make progress, so the read buffer is not grown
+            // to hold the skipped message.
+            let n = cmp::min(skip, self.buffered_len() as u64);
+            self.buf_offset += n as usize;
+            if n < skip {
+                self.skip = Some(skip - n);
+                self.needed = 1;
+                return Ok(false);
+            }
+            self.skip = None;
+        }
+
+        if self.remaining_segments.is_empty() {
+            self.parse_segment_table()
+        } else {
+            Ok(true)
+        }
+    }
+
+    /// Decodes the next message from the buffered bytes, or returns `None` if
+    /// the entire message has not yet been buffered.
+    fn decode(&mut self) -> Result<Option<Reader<Segments>>> {
+        if !try!(self.begin_message()) {
+            return Ok(None);
+        }
+
+        while let Some(&segment_len) = self.remaining_segments.last() {
+            if self.buffered_len() < segment_len {
+                self.needed = segment_len;
+                return Ok(None);
+            }
+            self.segments.push(self.buf.buf(self.buf_offset, segment_len));
+            self.buf_offset += segment_len;
+            self.remaining_segments.pop();
+        }
+
+        let message_len = self.segments.iter().fold((self.segments.len() / 2 + 1) * 8,
+                                                    |acc, segment| acc + segment.len());
+        self.record_message_len(message_len);
+        self.needed = 8;
+
+        Ok(Some(Reader::new(Segments { segments: mem::take(&mut self.segments) },
+                            self.options.clone())))
+    }
+
+    /// Updates the moving average of message lengths with the length of a read
+    /// message.
+    fn record_message_len(&mut self, len: usize) {
+        self.average_message_len = if self.average_message_len == 0 {
+            len
+        } else {
+            self.average_message_len - self.average_message_len / 8 + len / 8
+        };
+    }
+
+    /// Returns the bytes which have been received from the stream, but not
+    /// taken out of the decoder as messages, beginning with the partially read
+    /// message, if any. The bytes are in the serialization format of the
+    /// stream, so that the rest of the stream may be appended to them.
+    ///
+    /// Fails if the decoder has been poisoned, or if the stream is in the
+    /// middle of a skipped message, since the bytes would not begin at a
+    /// message boundary. Fails as well if the packed stream is in the middle
+    /// of a run of verbatim words which can not be packed again, in which case
+    /// the error wraps an `UnpackedRemainder` holding the unpacked bytes.
+    pub fn into_buffered(mut self) -> Result<Vec<u8>> {
+        try!(self.poison.check());
+
+        // Unpack the remaining input, so that the rest of a skipped message
+        // may be discarded.
+        if self.unpacker.is_some() {
+            loop {
+                let amount = self.reserve_amount();
+                self.reserve(amount);
+                let Decoder { ref mut buf, ref mut unpacker, .. } = self;
+                match unpacker.as_mut().unwrap().unpack(buf.spare_mut()) {
+                    0 => break,
+                    n => buf.commit(n),
+                }
+            }
+        }
+
+        if let Some(skip) = self.skip {
+            if skip > self.buffered_len() as u64 {
+                return Err(Error::new(ErrorKind::InvalidData,
+                                      "stream is in the middle of a skipped message"));
+            }
+            self.buf_offset += skip as usize;
+        }
+
+        // The segment table and segments of the partially read message have
+        // already been consumed from the read buffer.
+        let mut bytes = Vec::new();
+        if !self.remaining_segments.is_empty() {
+            let segment_lens = self.segments.iter()
+                                            .map(|segment| segment.len())
+                                            .chain(self.remaining_segments.iter().rev().cloned())
+                                            .collect::<Vec<_>>();
+            let mut word = [0; 4];
+            <LittleEndian as ByteOrder>::write_u32(&mut word, segment_lens.len() as u32 - 1);
+            bytes.extend_from_slice(&word);
+            for &len in &segment_lens {
+                <LittleEndian as ByteOrder>::write_u32(&mut word, (len / 8) as u32);
+                bytes.extend_from_slice(&word);
+            }
+            if segment_lens.len() % 2 == 0 {
+                bytes.extend_from_slice(&[0, 0, 0, 0]);
+            }
+            for segment in &self.segments {
+                bytes.extend_from_slice(segment);
+            }
+        }
+        bytes.extend_from_slice(&self.buf[self.buf_offset..]);
+
+        match self.unpacker {
+            Some(unpacker) => unpacker.into_packed(bytes),
+            None => Ok(bytes),
+        }
+    }
+
+    /// Returns the next message from the stream, or `None` if the entire
+    /// message is not yet available, or if the stream has been closed at a
+    /// message boundary.
+    ///
+    /// If an error is returned, the decoder is poisoned, and every further
+    /// call fails, unless the error reports a `SkippedMessage`.
+    pub fn read_message<R>(&mut self, inner: &mut R) -> Result<Option<Reader<Segments>>>
+    where R: io::Read {
+        let result = self.read(inner, Decoder::next_message);
+        self.record(result)
+    }
+
+    /// Returns the segment table of the next message from the stream, once it
+    /// has been read, without taking the message out of the decoder. Returns
+    /// `None` if the segment table is not yet available, or if the stream has
+    /// been closed at a message boundary.
+    ///
+    /// Errors are returned and recorded as by `read_message`.
+    pub fn peek_message_header<R>(&mut self, inner: &mut R) -> Result<Option<MessageHeader>>
+    where R: io::Read {
+        let result = self.read(inner, Decoder::peek_header);
+        self.record(result)
+    }
+
+    /// Reads from the stream until `decode` returns a value, the stream would
+    /// block, or the stream is closed, without poisoning the decoder.
+    fn read<R, T, F>(&mut self, inner: &mut R, mut decode: F) -> Result<Option<T>>
+    where R: io::Read, F: FnMut(&mut Decoder) -> Result<Option<T>> {
+        loop {
+            if let Some(decoded) = try!(decode(self)) {
+                return Ok(Some(decoded));
+            }
+            if self.eof {
+                return Ok(None);
+            }
+            match self.read_from(inner) {
+                Ok(0) => self.push_eof(),
+                Ok(_) => (),
+                Err(ref error) if error.kind() == ErrorKind::WouldBlock => return Ok(None),
+                Err(ref error) if error.kind() == ErrorKind::Interrupted => (),
+                Err(error) => return Err(error),
+            }
+        }
+    }
+}
+
+/// Converts a length to `usize`, saturating at `usize::MAX`.
+fn saturate(len: u64) -> usize {
+    cmp::min(len, usize::MAX as u64) as usize
+}
+
+/// Parses a segment table into a sequence of segment lengths, and adds the
+/// lengths to the provided `Vec`.
+///
+/// Returns 0 if the parse succeeded, otherwise returns the number of bytes
+/// required to make progress with the parse. Segment tables with more than
+/// `max_segments` segments are rejected.
+pub fn parse_segment_table(buf: &[u8], max_segments: usize, lengths: &mut Vec<usize>) -> Result<usize> {
+    if buf.len() < 8 { return Ok(8); }
+    let segment_count = <LittleEndian as ByteOrder>::read_u32(&buf[0..4])
+                                                    .wrapping_add(1) as usize;
+
+    if segment_count > max_segments {
+        return result::Result::Err(From::from(LimitError::TooManySegments { count: segment_count,
+                                                                            limit: max_segments }));
+    } else if segment_count == 0 {
+        return result::Result::Err(Error::new(ErrorKind::InvalidData,
+                                              "zero segments Cap'n Proto message".to_string()));
+    }
+
+    let len = (segment_count / 2 + 1) * 8;
+    if buf.len() < len { return Ok(len); }
+
+    for segment in 0..segment_count {
+        let offset = (segment + 1) * 4;
+        let segment_len = <LittleEndian as ByteOrder>::read_u32(&buf[offset..]) as usize;
+        lengths.push(segment_len * 8);
+    }
+
+    Ok(0)
+}
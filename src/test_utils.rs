@@ -1,12 +1,21 @@
 //! Test utilities.
 
-use std::io::{self, Read, Write};
+use std::io::{self, Cursor, IoSlice, Read, Write};
 use std::cmp;
 
-use capnp::Word;
+use capnp::{Word, data, message};
 
 use byteorder::{ByteOrder, LittleEndian};
 
+use ShutdownWrite;
+
+/// Returns a message whose root is the provided data.
+pub fn data_message(data: &[u8]) -> message::Builder<message::HeapAllocator> {
+    let mut message = message::Builder::new_default();
+    message.set_root::<data::Builder, _>(data).unwrap();
+    message
+}
+
 /// Writes segments as if they were a Capnproto message.
 ///
 /// This is copied from capnproto-rust, and exists that our read/write format
@@ -76,17 +85,24 @@ pub struct BlockingStream<S> {
 
     /// Number of bytes written since last blocking
     write_idx: usize,
+
+    /// Whether the write half has been shut down
+    shut_down: bool,
 }
 
 impl <S> BlockingStream<S> {
 
     pub fn new(stream: S, frequency: usize) -> BlockingStream<S> {
-        BlockingStream { stream: stream, frequency: frequency, read_idx: 0, write_idx: 0 }
+        BlockingStream { stream: stream, frequency: frequency, read_idx: 0, write_idx: 0, shut_down: false }
     }
 
     pub fn inner_mut(&mut self) -> &mut S {
         &mut self.stream
     }
+
+    pub fn is_shut_down(&self) -> bool {
+        self.shut_down
+    }
 }
 
 impl <R> Read for BlockingStream<R> where R: Read {
@@ -105,6 +121,7 @@ impl <R> Read for BlockingStream<R> where R: Read {
 
 impl <S> Write for BlockingStream<S> where S: Write {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        assert!(!self.shut_down, "write after shutdown");
         if self.write_idx == 0 {
             self.write_idx = self.frequency;
             Err(io::Error::new(io::ErrorKind::WouldBlock, "BlockingStream"))
@@ -116,7 +133,70 @@ impl <S> Write for BlockingStream<S> where S: Write {
         }
     }
 
+    fn write_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+        assert!(!self.shut_down, "write after shutdown");
+        if self.write_idx == 0 {
+            self.write_idx = self.frequency;
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "BlockingStream"));
+        }
+
+        // Truncate the slices to the number of bytes remaining before blocking.
+        let mut remaining = self.write_idx;
+        let mut truncated = Vec::new();
+        for buf in bufs {
+            if remaining == 0 { break; }
+            let len = cmp::min(remaining, buf.len());
+            truncated.push(IoSlice::new(&buf[..len]));
+            remaining -= len;
+        }
+
+        let bytes_written = try!(self.stream.write_vectored(&truncated));
+        self.write_idx -= bytes_written;
+        Ok(bytes_written)
+    }
+
     fn flush(&mut self) -> io::Result<()> {
         self.stream.flush()
     }
 }
+
+impl <S> ShutdownWrite for BlockingStream<S> {
+    fn shutdown_write(&mut self) -> io::Result<()> {
+        self.shut_down = true;
+        Ok(())
+    }
+}
+
+/// A non-blocking stream which reads from `input`, and writes to `output`. The
+/// stream would block once `input` is exhausted, and once `capacity` bytes
+/// have been written.
+pub struct DuplexStream {
+    pub input: Cursor<Vec<u8>>,
+    pub output: Vec<u8>,
+    pub capacity: usize,
+}
+
+impl Read for DuplexStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match try!(self.input.read(buf)) {
+            0 => Err(io::Error::new(io::ErrorKind::WouldBlock, "DuplexStream")),
+            n => Ok(n),
+        }
+    }
+}
+
+impl Write for DuplexStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.capacity == 0 {
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "DuplexStream"));
+        }
+        let len = cmp::min(self.capacity, buf.len());
+        self.output.extend_from_slice(&buf[..len]);
+        self.capacity -= len;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
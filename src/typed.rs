@@ -0,0 +1,237 @@
+//! Message streams which read and write messages with a specific root type.
+
+use std::fmt;
+use std::io::{self, Result};
+use std::marker::PhantomData;
+use std::result;
+
+use capnp;
+use capnp::message::{Allocator, Builder, HeapAllocator, Reader};
+use capnp::traits::Owned;
+
+use outbound::OutboundMessage;
+use {Completed, MessageStream, Segments, Ticket, WriteError};
+
+/// A message read from a stream, whose root has been validated as the type `T`.
+pub struct TypedReader<T> {
+    message: Reader<Segments>,
+    marker: PhantomData<T>,
+}
+
+impl <T> TypedReader<T> where T: for<'a> Owned<'a> {
+
+    /// Wraps a message, and validates that its root is of the type `T`.
+    pub fn new(message: Reader<Segments>) -> capnp::Result<TypedReader<T>> {
+        try!(TypedReader::<T>::root(&message));
+        Ok(TypedReader { message: message, marker: PhantomData })
+    }
+
+    fn root<'a>(message: &'a Reader<Segments>) -> capnp::Result<<T as Owned<'a>>::Reader> {
+        message.get_root()
+    }
+
+    /// Returns the root of the message.
+    pub fn get<'a>(&'a self) -> <T as Owned<'a>>::Reader {
+        TypedReader::<T>::root(&self.message).expect("message root was validated when read")
+    }
+}
+
+impl <T> TypedReader<T> {
+
+    /// Unwraps the message.
+    pub fn into_inner(self) -> Reader<Segments> {
+        self.message
+    }
+}
+
+/// A message builder whose root is of the type `T`.
+pub struct TypedBuilder<T, A=HeapAllocator> where A: Allocator {
+    message: Builder<A>,
+    marker: PhantomData<T>,
+}
+
+impl <T> TypedBuilder<T, HeapAllocator> {
+
+    /// Creates a new message builder with the default allocator.
+    pub fn new_default() -> TypedBuilder<T, HeapAllocator> {
+        TypedBuilder::new(HeapAllocator::new())
+    }
+}
+
+impl <T, A> TypedBuilder<T, A> where A: Allocator {
+
+    /// Creates a new message builder with the provided allocator.
+    pub fn new(allocator: A) -> TypedBuilder<T, A> {
+        TypedBuilder { message: Builder::new(allocator), marker: PhantomData }
+    }
+
+    /// Unwraps the message builder.
+    pub fn into_inner(self) -> Builder<A> {
+        self.message
+    }
+}
+
+impl <T, A> TypedBuilder<T, A> where T: for<'a> Owned<'a>, A: Allocator {
+
+    /// Initializes the root of the message.
+    pub fn init_root<'a>(&'a mut self) -> <T as Owned<'a>>::Builder {
+        self.message.init_root()
+    }
+
+    /// Returns the root of the message.
+    pub fn get_root<'a>(&'a mut self) -> capnp::Result<<T as Owned<'a>>::Builder> {
+        self.message.get_root()
+    }
+
+    /// Sets the root of the message to a copy of `value`.
+    pub fn set_root<'a>(&mut self, value: <T as Owned<'a>>::Reader) -> capnp::Result<()> {
+        self.message.set_root::<<T as Owned<'a>>::Builder, _>(value)
+    }
+
+    /// Returns the root of the message as a reader.
+    pub fn get_root_as_reader<'a>(&'a self) -> capnp::Result<<T as Owned<'a>>::Reader> {
+        self.message.get_root_as_reader()
+    }
+}
+
+impl <T, A> OutboundMessage for TypedBuilder<T, A> where A: Allocator {
+    fn output_segments<'a>(&'a self, segments: &mut Vec<&'a [u8]>) {
+        self.message.output_segments(segments)
+    }
+}
+
+/// Wraps a `MessageStream`, and reads messages whose root is of the type `In`,
+/// and writes messages whose root is of the type `Out`. `In` and `Out` are the
+/// `Owned` types generated for Cap'n Proto structs.
+///
+/// The root of each inbound message is validated when the message is read, so
+/// that a message of the wrong type fails to read with a single error.
+///
+/// Outbound messages are built with the allocator `A`.
+pub struct TypedMessageStream<S, In, Out, A=HeapAllocator> where A: Allocator {
+    stream: MessageStream<S, TypedBuilder<Out, A>>,
+    marker: PhantomData<In>,
+}
+
+impl <S, In, Out, A> TypedMessageStream<S, In, Out, A> where A: Allocator {
+
+    /// Creates a new `TypedMessageStream` wrapping the provided message stream.
+    pub fn new(stream: MessageStream<S, TypedBuilder<Out, A>>) -> TypedMessageStream<S, In, Out, A> {
+        TypedMessageStream { stream: stream, marker: PhantomData }
+    }
+
+    /// Returns the wrapped message stream.
+    pub fn get_ref(&self) -> &MessageStream<S, TypedBuilder<Out, A>> {
+        &self.stream
+    }
+
+    /// Returns the wrapped message stream.
+    pub fn get_mut(&mut self) -> &mut MessageStream<S, TypedBuilder<Out, A>> {
+        &mut self.stream
+    }
+
+    /// Unwraps the message stream.
+    pub fn into_inner(self) -> MessageStream<S, TypedBuilder<Out, A>> {
+        self.stream
+    }
+}
+
+impl <S, In, Out, A> TypedMessageStream<S, In, Out, A> where S: io::Read, In: for<'a> Owned<'a>, A: Allocator {
+
+    /// Returns the next message from the stream, or `None` if the entire
+    /// message is not yet available, or if the stream has been closed, as by
+    /// `MessageStream::read_message`.
+    ///
+    /// If the root of the message is not of the type `In`, an `InvalidData`
+    /// error is returned. The message is consumed, and the stream is not
+    /// poisoned, so the next message may be read.
+    pub fn read_message(&mut self) -> Result<Option<TypedReader<In>>> {
+        match try!(self.stream.read_message()) {
+            Some(message) => TypedReader::new(message)
+                .map(Some)
+                .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error)),
+            None => Ok(None),
+        }
+    }
+}
+
+impl <S, In, Out, A> TypedMessageStream<S, In, Out, A> where S: io::Write, A: Allocator {
+
+    /// Writes queued messages to the stream, as by `MessageStream::write`.
+    pub fn write(&mut self) -> io::Result<Completed> {
+        self.stream.write()
+    }
+
+    /// Queue message for write, as by `MessageStream::write_message`.
+    pub fn write_message(&mut self, message: TypedBuilder<Out, A>)
+                         -> result::Result<Ticket, WriteError<TypedBuilder<Out, A>>> {
+        self.stream.write_message(message)
+    }
+}
+
+impl <S, In, Out, A> fmt::Debug for TypedMessageStream<S, In, Out, A> where S: fmt::Debug, A: Allocator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "TypedMessageStream {{ stream: {:?} }}", self.stream)
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use std::io::{Cursor, ErrorKind};
+
+    use capnp::{data, message, serialize, text, Word};
+
+    use MessageStream;
+    use super::{TypedBuilder, TypedMessageStream};
+
+    #[test]
+    fn test_typed_message_stream() {
+        let mut input = Vec::new();
+        let mut message = message::Builder::new_default();
+        message.set_root::<text::Builder, _>("abcdefgh").unwrap();
+        serialize::write_message(&mut input, &message).unwrap();
+        // Data is not NUL terminated, so it is not valid text.
+        let mut message = message::Builder::new_default();
+        message.set_root::<data::Builder, _>(&b"ijklmnop"[..]).unwrap();
+        serialize::write_message(&mut input, &message).unwrap();
+        let mut message = message::Builder::new_default();
+        message.set_root::<text::Builder, _>("qrstuvwx").unwrap();
+        serialize::write_message(&mut input, &message).unwrap();
+
+        let input_len = input.len();
+        let stream = MessageStream::new(Cursor::new(input), message::ReaderOptions::new());
+        let mut stream = TypedMessageStream::<_, text::Owned, data::Owned>::new(stream);
+        assert_eq!("abcdefgh", stream.read_message().unwrap().unwrap().get());
+        assert_eq!(ErrorKind::InvalidData, stream.read_message().err().unwrap().kind());
+        assert_eq!("qrstuvwx", stream.read_message().unwrap().unwrap().get());
+        assert!(stream.read_message().unwrap().is_none());
+
+        let mut response = TypedBuilder::<data::Owned>::new_default();
+        response.set_root(&b"yz012345"[..]).unwrap();
+        stream.write_message(response).unwrap();
+
+        // The response is written after the end of the input.
+        let mut output = Cursor::new(stream.into_inner().inner().get_ref().clone());
+        output.set_position(input_len as u64);
+        let message = serialize::read_message(&mut output, message::ReaderOptions::new()).unwrap();
+        assert_eq!(b"yz012345", message.get_root::<data::Reader>().unwrap());
+    }
+
+    #[test]
+    fn test_typed_message_stream_allocator() {
+        let mut words = Word::allocate_zeroed_vec(32);
+        let mut scratch_space = message::ScratchSpace::new(&mut words);
+        let mut response = TypedBuilder::<data::Owned, _>::new(
+            message::ScratchSpaceHeapAllocator::new(&mut scratch_space));
+        response.set_root(&b"abcdefgh"[..]).unwrap();
+
+        let stream = MessageStream::new(Cursor::new(Vec::new()), message::ReaderOptions::new());
+        let mut stream = TypedMessageStream::<_, text::Owned, _, _>::new(stream);
+        stream.write_message(response).unwrap();
+
+        let mut output = Cursor::new(stream.into_inner().inner().get_ref().clone());
+        let message = serialize::read_message(&mut output, message::ReaderOptions::new()).unwrap();
+        assert_eq!(b"abcdefgh", message.get_root::<data::Reader>().unwrap());
+    }
+}
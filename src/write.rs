@@ -0,0 +1,958 @@
+//! Writing Cap'n Proto messages to a stream.
+
+use std::collections::VecDeque;
+use std::{cmp, error, fmt, mem, usize};
+use std::io::{self, IoSlice};
+use std::net::{Shutdown, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::result;
+
+use byteorder::{ByteOrder, LittleEndian};
+use capnp::message::{
+    Builder,
+    HeapAllocator,
+};
+
+use outbound::OutboundMessage;
+use packed;
+use poison::Poison;
+
+/// The maximum number of slices submitted in a single vectored write.
+const MAX_SLICES: usize = 64;
+
+/// Default high watermark of the outbound queue.
+const HIGH_WATERMARK: usize = 64 * 1024;
+
+/// Default low watermark of the outbound queue.
+const LOW_WATERMARK: usize = 16 * 1024;
+
+/// Options controlling the limits of the outbound message queue.
+///
+/// The queue is full once it holds `max_messages` messages, or at least
+/// `max_bytes` bytes, and messages are rejected while it is full. Since the
+/// limits are checked before a message is queued, the message which fills the
+/// queue may take it past `max_bytes`, and a single message larger than
+/// `max_bytes` may still be written. An empty queue is never full, so even
+/// with `max_messages` set to 0, one message is admitted into an empty queue.
+///
+/// The watermarks do not limit the queue; they allow the caller to stop
+/// producing outbound messages while the queue is above the high watermark,
+/// and to resume once it has drained below the low watermark.
+///
+/// The length of a queued message includes its segment table, and is counted
+/// against the queue until the message has been completely written.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct QueueOptions {
+    /// The maximum number of queued messages. Defaults to unlimited.
+    pub max_messages: usize,
+
+    /// The maximum total length of queued messages, in bytes. Defaults to
+    /// unlimited.
+    pub max_bytes: usize,
+
+    /// The total length of queued messages in bytes at or above which the queue
+    /// is above its high watermark. Defaults to 64KiB.
+    pub high_watermark: usize,
+
+    /// The total length of queued messages in bytes at or below which the
+    /// queue is below its low watermark. Defaults to 16KiB.
+    pub low_watermark: usize,
+
+    /// Whether completely written messages are retained, so that they may be
+    /// taken with `take_written_messages` and reused, rather than dropped.
+    /// Defaults to `false`.
+    pub retain_written: bool,
+}
+
+impl Default for QueueOptions {
+    fn default() -> QueueOptions {
+        QueueOptions {
+            max_messages: usize::MAX,
+            max_bytes: usize::MAX,
+            high_watermark: HIGH_WATERMARK,
+            low_watermark: LOW_WATERMARK,
+            retain_written: false,
+        }
+    }
+}
+
+impl QueueOptions {
+    pub fn new() -> QueueOptions {
+        QueueOptions::default()
+    }
+
+    pub fn max_messages(&mut self, value: usize) -> &mut QueueOptions {
+        self.max_messages = value;
+        self
+    }
+
+    pub fn max_bytes(&mut self, value: usize) -> &mut QueueOptions {
+        self.max_bytes = value;
+        self
+    }
+
+    pub fn high_watermark(&mut self, value: usize) -> &mut QueueOptions {
+        self.high_watermark = value;
+        self
+    }
+
+    pub fn low_watermark(&mut self, value: usize) -> &mut QueueOptions {
+        self.low_watermark = value;
+        self
+    }
+
+    pub fn retain_written(&mut self, value: bool) -> &mut QueueOptions {
+        self.retain_written = value;
+        self
+    }
+}
+
+/// An error returned by `write_message`.
+pub enum WriteError<M> {
+    /// The outbound queue is full. The message has not been queued, and is
+    /// handed back.
+    QueueFull(M),
+    /// The write half of the stream has been closed. The message has not been
+    /// queued, and is handed back.
+    Closed(M),
+    /// The stream has been poisoned by a previous error. The message has not
+    /// been queued, and is handed back along with an error describing the
+    /// previous error.
+    Poisoned(M, io::Error),
+    /// Writing to the stream failed. The message has been queued.
+    Io(io::Error),
+}
+
+impl <M> From<io::Error> for WriteError<M> {
+    fn from(error: io::Error) -> WriteError<M> {
+        WriteError::Io(error)
+    }
+}
+
+impl <M> From<WriteError<M>> for io::Error {
+    fn from(error: WriteError<M>) -> io::Error {
+        match error {
+            WriteError::QueueFull(_) => io::Error::other("outbound queue is full"),
+            WriteError::Closed(_) => io::Error::new(io::ErrorKind::BrokenPipe, "stream is closed for writing"),
+            WriteError::Poisoned(_, error) | WriteError::Io(error) => error,
+        }
+    }
+}
+
+impl <M> fmt::Debug for WriteError<M> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            WriteError::QueueFull(_) => write!(f, "QueueFull(..)"),
+            WriteError::Closed(_) => write!(f, "Closed(..)"),
+            WriteError::Poisoned(_, ref error) => write!(f, "Poisoned(.., {:?})", error),
+            WriteError::Io(ref error) => write!(f, "Io({:?})", error),
+        }
+    }
+}
+
+impl <M> fmt::Display for WriteError<M> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            WriteError::QueueFull(_) => write!(f, "outbound queue is full"),
+            WriteError::Closed(_) => write!(f, "stream is closed for writing"),
+            WriteError::Poisoned(_, ref error) | WriteError::Io(ref error) => fmt::Display::fmt(error, f),
+        }
+    }
+}
+
+impl <M> error::Error for WriteError<M> {}
+
+/// A stream whose write half may be shut down, once every queued message has
+/// been written.
+pub trait ShutdownWrite {
+    /// Shuts down the write half of the stream, so that the peer reads the end
+    /// of the stream.
+    fn shutdown_write(&mut self) -> io::Result<()>;
+}
+
+impl ShutdownWrite for TcpStream {
+    fn shutdown_write(&mut self) -> io::Result<()> {
+        self.shutdown(Shutdown::Write)
+    }
+}
+
+#[cfg(unix)]
+impl ShutdownWrite for UnixStream {
+    fn shutdown_write(&mut self) -> io::Result<()> {
+        self.shutdown(Shutdown::Write)
+    }
+}
+
+#[cfg(feature = "mio")]
+impl ShutdownWrite for ::mio::net::TcpStream {
+    fn shutdown_write(&mut self) -> io::Result<()> {
+        self.shutdown(Shutdown::Write)
+    }
+}
+
+#[cfg(all(feature = "mio", unix))]
+impl ShutdownWrite for ::mio::net::UnixStream {
+    fn shutdown_write(&mut self) -> io::Result<()> {
+        self.shutdown(Shutdown::Write)
+    }
+}
+
+impl <S> ShutdownWrite for &mut S where S: ShutdownWrite + ?Sized {
+    fn shutdown_write(&mut self) -> io::Result<()> {
+        (**self).shutdown_write()
+    }
+}
+
+impl <S> ShutdownWrite for Box<S> where S: ShutdownWrite + ?Sized {
+    fn shutdown_write(&mut self) -> io::Result<()> {
+        (**self).shutdown_write()
+    }
+}
+
+/// Identifies a queued outbound message. Tickets are issued in the order in
+/// which messages are queued, and messages are written in the same order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Ticket(u64);
+
+/// The tickets of outbound messages which have been completely written to the
+/// stream, in the order in which they were written.
+///
+/// Only messages which have been written are completed. The tickets of
+/// messages which were removed from the queue before being written, by
+/// `clear_outbound_queue`, `cancel_message` or `cancel_messages`, are never
+/// completed.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Completed {
+    /// The completed tickets, in ascending order.
+    tickets: Vec<Ticket>,
+    /// The index of the next ticket returned by the iterator.
+    next: usize,
+}
+
+impl Completed {
+
+    /// Returns `true` if no message has been completed.
+    pub fn is_empty(&self) -> bool {
+        self.next == self.tickets.len()
+    }
+
+    /// Returns `true` if the message with the provided ticket has been
+    /// completed, and has not yet been returned by the iterator.
+    pub fn contains(&self, ticket: Ticket) -> bool {
+        self.tickets[self.next..].binary_search(&ticket).is_ok()
+    }
+}
+
+impl Iterator for Completed {
+    type Item = Ticket;
+
+    fn next(&mut self) -> Option<Ticket> {
+        let ticket = self.tickets.get(self.next).cloned();
+        if ticket.is_some() {
+            self.next += 1;
+        }
+        ticket
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.tickets.len() - self.next;
+        (len, Some(len))
+    }
+}
+
+impl Extend<Ticket> for Completed {
+    /// Appends tickets which were completed after the tickets already held.
+    fn extend<I>(&mut self, tickets: I) where I: IntoIterator<Item=Ticket> {
+        self.tickets.extend(tickets);
+    }
+}
+
+/// A message in the outbound queue.
+struct QueuedMessage<M> {
+    message: M,
+    ticket: Ticket,
+    /// The length of the serialized message, in bytes.
+    len: usize,
+}
+
+/// The write half of the message stream protocol, decoupled from IO.
+///
+/// Messages are queued with `queue_message`. The bytes which remain to be
+/// written are returned by `outbound_slices`, and once some of them have been
+/// written to the stream, the encoder is advanced past them with `consume`.
+pub struct Encoder<M=Builder<HeapAllocator>> {
+    /// Queue of outbound messages which have not yet begun being written to the
+    /// stream.
+    outbound_queue: VecDeque<QueuedMessage<M>>,
+
+    /// The total length of the queued messages, in bytes.
+    queued_bytes: usize,
+
+    /// Options controlling the limits of the outbound queue.
+    queue_options: QueueOptions,
+
+    /// Completely written messages, if they are retained.
+    written: Vec<M>,
+
+    /// The serialized segment tables of the messages currently being written
+    /// to the stream.
+    segment_tables: Vec<u8>,
+
+    /// The progress of the current write. The message currently being written
+    /// is a the front of the outbound queue.
+    ///
+    /// The first corresponds to the segment currently being written, offset by
+    /// 1, or 0 if the segment table is being written. The second corresponds to
+    /// the offset within the current segment.
+    write_progress: Option<(usize, usize)>,
+
+    /// Whether messages are written in the packed encoding.
+    packed: bool,
+
+    /// The packed bytes of consecutive segment tables and segments, beginning
+    /// with the one currently being written, if the stream is packed.
+    packed_parts: VecDeque<Vec<u8>>,
+
+    /// The position following the last of `packed_parts`, as the index of its
+    /// message in the outbound queue, and the index of the part within the
+    /// message, where 0 is the segment table.
+    packed_end: (usize, usize),
+
+    /// The ticket of the next queued message.
+    next_ticket: u64,
+
+    /// The tickets of the messages which have been completely written since
+    /// the previous call to `take_completed`.
+    completed: Vec<Ticket>,
+
+    /// Whether the encoder has been closed, so that no further message may be
+    /// queued.
+    closed: bool,
+
+    /// Whether the write half of the stream has been shut down, after the
+    /// encoder was closed and the queue drained.
+    shut_down: bool,
+
+    /// Records fatal errors.
+    poison: Poison,
+}
+
+impl <M> Default for Encoder<M> {
+    fn default() -> Encoder<M> {
+        Encoder::new()
+    }
+}
+
+impl <M> Encoder<M> {
+
+    /// Creates a new encoder for unpacked messages.
+    pub fn new() -> Encoder<M> {
+        Encoder::with_packing(false)
+    }
+
+    /// Creates a new encoder for messages in the packed encoding.
+    pub fn new_packed() -> Encoder<M> {
+        Encoder::with_packing(true)
+    }
+
+    fn with_packing(packed: bool) -> Encoder<M> {
+        Encoder {
+            outbound_queue: VecDeque::new(),
+            queued_bytes: 0,
+            queue_options: QueueOptions::default(),
+            written: Vec::new(),
+            segment_tables: Vec::new(),
+            write_progress: None,
+            packed: packed,
+            packed_parts: VecDeque::new(),
+            packed_end: (0, 0),
+            next_ticket: 0,
+            completed: Vec::new(),
+            closed: false,
+            shut_down: false,
+            poison: Poison::new(),
+        }
+    }
+
+    /// Returns `true` if a previous write error has poisoned the encoder.
+    pub fn is_poisoned(&self) -> bool {
+        self.poison.is_poisoned()
+    }
+
+    /// Closes the encoder, so that no further message may be queued. Messages
+    /// which have already been queued are still written.
+    pub fn close(&mut self) {
+        self.closed = true;
+    }
+
+    /// Returns `true` if the encoder has been closed.
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    /// Returns `true` if the write half of the stream has been shut down.
+    pub fn is_shut_down(&self) -> bool {
+        self.shut_down
+    }
+
+    /// Returns `true` if the encoder writes messages in the packed encoding.
+    pub fn is_packed(&self) -> bool {
+        self.packed
+    }
+
+    /// Returns the number of queued outbound messages.
+    pub fn outbound_queue_len(&self) -> usize {
+        self.outbound_queue.len()
+    }
+
+    /// Returns the total length of the queued outbound messages, in bytes.
+    pub fn outbound_queue_bytes(&self) -> usize {
+        self.queued_bytes
+    }
+
+    /// Sets the options controlling the limits of the outbound queue. Messages
+    /// which have already been queued are not affected.
+    pub fn set_queue_options(&mut self, options: QueueOptions) {
+        self.queue_options = options;
+    }
+
+    /// Returns `true` if the outbound queue is full, so that no further message
+    /// may be queued.
+    pub fn is_outbound_queue_full(&self) -> bool {
+        !self.outbound_queue.is_empty()
+            && (self.outbound_queue.len() >= self.queue_options.max_messages
+                || self.queued_bytes >= self.queue_options.max_bytes)
+    }
+
+    /// Returns `true` if the outbound queue is at or above its high watermark.
+    pub fn is_above_high_watermark(&self) -> bool {
+        self.queued_bytes >= self.queue_options.high_watermark
+    }
+
+    /// Returns `true` if the outbound queue is at or below its low watermark.
+    pub fn is_below_low_watermark(&self) -> bool {
+        self.queued_bytes <= self.queue_options.low_watermark
+    }
+
+    /// Clears the outbound message queue of all messages that have not begun
+    /// writing yet. The cleared messages are not completed.
+    pub fn clear_outbound_queue(&mut self) {
+        let start = self.started_len();
+        self.outbound_queue.truncate(start);
+        self.queued_bytes = self.outbound_queue.iter().fold(0, |acc, queued| acc + queued.len);
+        self.packed_parts.clear();
+    }
+
+    /// Removes the queued message with the provided ticket, and returns it. A
+    /// message which has begun writing cannot be cancelled, and `None` is
+    /// returned for it, as for a message which is no longer queued. A cancelled
+    /// message is not completed.
+    pub fn cancel_message(&mut self, ticket: Ticket) -> Option<M> {
+        let start = self.started_len();
+        let position = self.outbound_queue.iter().skip(start).position(|queued| queued.ticket == ticket);
+        let index = match position {
+            Some(index) => index + start,
+            None => return None,
+        };
+        let queued = self.outbound_queue.remove(index).unwrap();
+        self.queued_bytes -= queued.len;
+        self.packed_parts.clear();
+        Some(queued.message)
+    }
+
+    /// Removes the queued messages which have not begun writing and for which
+    /// the predicate returns `true`, and returns them in queue order. Cancelled
+    /// messages are not completed.
+    pub fn cancel_messages<F>(&mut self, mut predicate: F) -> Vec<M>
+    where F: FnMut(Ticket, &M) -> bool {
+        let mut cancelled = Vec::new();
+        let mut index = self.started_len();
+        while index < self.outbound_queue.len() {
+            let cancel = {
+                let queued = &self.outbound_queue[index];
+                predicate(queued.ticket, &queued.message)
+            };
+            if cancel {
+                let queued = self.outbound_queue.remove(index).unwrap();
+                self.queued_bytes -= queued.len;
+                cancelled.push(queued.message);
+            } else {
+                index += 1;
+            }
+        }
+        if !cancelled.is_empty() {
+            self.packed_parts.clear();
+        }
+        cancelled
+    }
+
+    /// Returns the number of messages at the front of the queue which have
+    /// begun writing, which is at most 1.
+    fn started_len(&self) -> usize {
+        if self.write_progress.is_some() { 1 } else { 0 }
+    }
+
+    /// Returns the tickets of the messages which have been completely written
+    /// since the previous call to `take_completed`.
+    pub fn take_completed(&mut self) -> Completed {
+        Completed { tickets: mem::take(&mut self.completed), next: 0 }
+    }
+
+    /// Returns the completely written messages which have been retained since
+    /// the previous call, in the order in which they were written. Messages are
+    /// only retained if `QueueOptions::retain_written` is set.
+    pub fn take_written_messages(&mut self) -> Vec<M> {
+        mem::take(&mut self.written)
+    }
+
+    /// Removes the completely written message from the front of the queue, and
+    /// retains it if configured to.
+    fn pop_message(&mut self) {
+        let queued = self.outbound_queue.pop_front().unwrap();
+        self.queued_bytes -= queued.len;
+        self.completed.push(queued.ticket);
+        if self.queue_options.retain_written {
+            self.written.push(queued.message);
+        }
+        self.write_progress = None;
+        if self.packed_end.0 > 0 {
+            self.packed_end.0 -= 1;
+        }
+    }
+}
+
+impl <M> Encoder<M> where M: OutboundMessage {
+
+    /// Queue message for write, without writing to the stream, and return its
+    /// ticket. If the outbound queue is full, or the encoder has been closed,
+    /// the message is not queued, and is handed back.
+    pub fn queue_message(&mut self, message: M) -> result::Result<Ticket, M> {
+        if self.closed || self.is_outbound_queue_full() {
+            return Err(message);
+        }
+        let len = {
+            let mut segments = Vec::new();
+            message.output_segments(&mut segments);
+            message_len(&segments)
+        };
+        let ticket = Ticket(self.next_ticket);
+        self.next_ticket += 1;
+        self.queued_bytes += len;
+        self.outbound_queue.push_back(QueuedMessage { message: message, ticket: ticket, len: len });
+        Ok(ticket)
+    }
+
+    /// Returns the bytes of the queued messages which remain to be written,
+    /// beginning with the remainder of the message currently being written.
+    /// Returns no slices if the queue is empty.
+    ///
+    /// The segment tables and segments of as many messages as fit in a single
+    /// vectored write are returned. In the packed encoding, each segment table
+    /// and segment is packed separately.
+    pub fn outbound_slices<'a>(&'a mut self) -> Vec<IoSlice<'a>> {
+        if self.is_packed() {
+            return self.outbound_packed_slices();
+        }
+
+        // Gather the messages which fit in the batch, and serialize their
+        // segment tables.
+        let mut message_count = 0;
+        let mut slice_count = 0;
+        let mut segments = Vec::new();
+        self.segment_tables.clear();
+        for queued in self.outbound_queue.iter() {
+            segments.clear();
+            queued.message.output_segments(&mut segments);
+            slice_count += segments.len() + 1;
+            if message_count > 0 && slice_count > MAX_SLICES {
+                break;
+            }
+            serialize_segment_table(&mut self.segment_tables, &segments);
+            message_count += 1;
+        }
+
+        let mut slices = Vec::new();
+        let mut progress = self.write_progress.unwrap_or((0, 0));
+        let mut table_offset = 0;
+        for queued in self.outbound_queue.iter().take(message_count) {
+            segments.clear();
+            queued.message.output_segments(&mut segments);
+            let table_len = segment_table_len(segments.len());
+            let table = &self.segment_tables[table_offset..table_offset + table_len];
+            table_offset += table_len;
+            message_slices(&mut slices, table, &segments, progress);
+            progress = (0, 0);
+        }
+        slices
+    }
+
+    /// Returns the remaining packed bytes of the segment tables and segments
+    /// which fit in a single vectored write.
+    fn outbound_packed_slices<'a>(&'a mut self) -> Vec<IoSlice<'a>> {
+        self.fill_packed_parts();
+        let offset = self.write_progress.map_or(0, |progress| progress.1);
+        self.packed_parts.iter()
+                         .enumerate()
+                         .map(|(i, part)| if i == 0 { &part[offset..] } else { &part[..] })
+                         .filter(|bytes| !bytes.is_empty())
+                         .map(IoSlice::new)
+                         .collect()
+    }
+
+    /// Packs the segment tables and segments following `packed_parts`, until
+    /// it holds as many parts as fit in a single vectored write, or every
+    /// queued message has been packed.
+    fn fill_packed_parts(&mut self) {
+        let Encoder {
+            ref outbound_queue,
+            ref mut segment_tables,
+            ref write_progress,
+            ref mut packed_parts,
+            ref mut packed_end,
+            ..
+        } = *self;
+
+        if packed_parts.is_empty() {
+            *packed_end = (0, write_progress.map_or(0, |progress| progress.0));
+        }
+
+        let mut segments = Vec::new();
+        while packed_parts.len() < MAX_SLICES {
+            let (message_index, part_index) = *packed_end;
+            let message = match outbound_queue.get(message_index) {
+                Some(queued) => &queued.message,
+                None => break,
+            };
+            segments.clear();
+            message.output_segments(&mut segments);
+            if part_index > segments.len() {
+                *packed_end = (message_index + 1, 0);
+                continue;
+            }
+
+            let mut packed_part = Vec::new();
+            if part_index == 0 {
+                segment_tables.clear();
+                serialize_segment_table(segment_tables, &segments);
+                packed::pack(segment_tables, &mut packed_part);
+            } else {
+                packed::pack(segments[part_index - 1], &mut packed_part);
+            }
+            packed_parts.push_back(packed_part);
+            *packed_end = (message_index, part_index + 1);
+        }
+    }
+
+    /// Advances the encoder past `n` bytes of the slices returned by
+    /// `outbound_slices`, which have been written to the stream. Completely
+    /// written messages are removed from the queue.
+    pub fn consume(&mut self, mut n: usize) {
+        if self.is_packed() {
+            return self.consume_packed(n);
+        }
+
+        loop {
+            let complete = {
+                let message = match self.outbound_queue.front() {
+                    Some(queued) => &queued.message,
+                    None => break,
+                };
+                // Don't begin the next message if there is nothing to advance.
+                if n == 0 && self.write_progress.is_none() {
+                    break;
+                }
+                let mut segments = Vec::new();
+                message.output_segments(&mut segments);
+                let progress = self.write_progress.get_or_insert((0, 0));
+                advance(segment_table_len(segments.len()), &segments, progress, &mut n)
+            };
+            if !complete {
+                break;
+            }
+            self.pop_message();
+        }
+        assert!(n == 0, "consumed more bytes than were outbound");
+    }
+
+    /// Like `consume`, but `n` is the number of packed bytes which have been
+    /// written.
+    fn consume_packed(&mut self, mut n: usize) {
+        loop {
+            if self.packed_parts.is_empty() {
+                self.fill_packed_parts();
+            }
+            let (segment_index, segment_offset) = self.write_progress.unwrap_or((0, 0));
+            let remaining = match self.packed_parts.front() {
+                Some(part) => part.len() - segment_offset,
+                None => break,
+            };
+            // Don't begin the next message if there is nothing to advance, but
+            // skip over empty segments, which have no packed bytes.
+            if n < remaining {
+                if n > 0 {
+                    self.write_progress = Some((segment_index, segment_offset + n));
+                    n = 0;
+                }
+                break;
+            }
+            n -= remaining;
+            self.packed_parts.pop_front();
+
+            let segment_count = {
+                let mut segments = Vec::new();
+                self.outbound_queue.front().unwrap().message.output_segments(&mut segments);
+                segments.len()
+            };
+            if segment_index < segment_count {
+                self.write_progress = Some((segment_index + 1, 0));
+            } else {
+                self.pop_message();
+            }
+        }
+        assert!(n == 0, "consumed more bytes than were outbound");
+    }
+
+    /// Writes queued messages to the stream, and returns the tickets of the
+    /// messages which have been completely written since the previous call to
+    /// `write`, including those written by `write_message`.
+    ///
+    /// If an error is returned, the encoder is poisoned, and every further
+    /// write fails.
+    pub fn write<W>(&mut self, inner: &mut W) -> io::Result<Completed> where W: io::Write {
+        try!(self.poison.check());
+        let result = self.write_queued(inner);
+        try!(self.poison.record(result));
+        Ok(self.take_completed())
+    }
+
+    /// Writes queued messages to the stream, without checking or poisoning the
+    /// encoder.
+    fn write_queued<W>(&mut self, inner: &mut W) -> io::Result<()> where W: io::Write {
+        loop {
+            let n = {
+                let slices = self.outbound_slices();
+                if slices.is_empty() {
+                    return Ok(());
+                }
+                match write_slices(inner, &slices) {
+                    Ok(n) => n,
+                    Err(ref error) if error.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                    Err(error) => return Err(error),
+                }
+            };
+            self.consume(n);
+        }
+    }
+
+    /// Queue message for write, and optimistically begin writing to the stream
+    /// if there is no message currently being written. Returns the ticket of
+    /// the message, which is reported by `write` once the message has been
+    /// completely written. If the outbound queue is full, the message is handed
+    /// back with `WriteError::QueueFull`.
+    ///
+    /// If the encoder has been poisoned by a previous error, the message is
+    /// handed back with `WriteError::Poisoned`. If the encoder has been closed,
+    /// the message is handed back with `WriteError::Closed`.
+    pub fn write_message<W>(&mut self, inner: &mut W, message: M) -> result::Result<Ticket, WriteError<M>>
+    where W: io::Write {
+        if let Err(error) = self.poison.check() {
+            return Err(WriteError::Poisoned(message, error));
+        }
+        if self.closed {
+            return Err(WriteError::Closed(message));
+        }
+        let ticket = match self.queue_message(message) {
+            Ok(ticket) => ticket,
+            Err(message) => return Err(WriteError::QueueFull(message)),
+        };
+
+        if self.outbound_queue_len() == 1 {
+            // Swallow NotConnected error when aggressively writing. OS X will
+            // return NotConnected when writing to a freshly opened non-blocking
+            // socket; see hoverbear/raft#61.
+            let result = match self.write_queued(inner) {
+                Err(ref error) if error.kind() == io::ErrorKind::NotConnected => Ok(()),
+                other => other,
+            };
+            try!(self.poison.record(result));
+        }
+        Ok(ticket)
+    }
+
+    /// Returns the queued messages which have not been completely written, in
+    /// queue order, and the number of bytes of the first message which have
+    /// already been written to the stream, in the serialization format of the
+    /// stream.
+    ///
+    /// For packed streams, the offset counts the bytes of the encoder's own
+    /// packing, which packs the segment table and each segment separately. It
+    /// only applies when the message is written again by an `Encoder`, and not
+    /// when it is packed as a whole, as by `capnp::serialize_packed`.
+    pub fn into_queued(self) -> (Vec<M>, usize) {
+        let written = match (self.outbound_queue.front(), self.write_progress) {
+            (Some(queued), Some((segment_index, segment_offset))) => {
+                let mut segments = Vec::new();
+                queued.message.output_segments(&mut segments);
+                let mut segment_table = Vec::new();
+                serialize_segment_table(&mut segment_table, &segments);
+                let parts = Some(&segment_table[..]).into_iter().chain(segments.iter().cloned());
+                parts.take(segment_index).fold(segment_offset, |acc, part| {
+                    if self.is_packed() {
+                        let mut packed_part = Vec::new();
+                        packed::pack(part, &mut packed_part);
+                        acc + packed_part.len()
+                    } else {
+                        acc + part.len()
+                    }
+                })
+            },
+            _ => 0,
+        };
+        (self.outbound_queue.into_iter().map(|queued| queued.message).collect(), written)
+    }
+
+    /// Closes the encoder, writes queued messages to the stream, and once every
+    /// queued message has been written, shuts down the write half of the
+    /// stream. Returns `true` once the stream has been shut down, or `false` if
+    /// queued messages remain, in which case `shutdown` should be called again
+    /// when the stream is writable.
+    ///
+    /// If an error is returned, the encoder is poisoned, and every further
+    /// write fails.
+    pub fn shutdown<W>(&mut self, inner: &mut W) -> io::Result<bool> where W: io::Write + ShutdownWrite {
+        try!(self.poison.check());
+        self.closed = true;
+        if !self.shut_down {
+            let result = self.write_queued(inner);
+            try!(self.poison.record(result));
+        }
+        self.shutdown_with(inner, W::shutdown_write)
+    }
+
+    /// Shuts down the write half of the stream with `shutdown_write`, if the
+    /// encoder has been closed, every queued message has been written, and the
+    /// stream has not already been shut down. Returns `true` once the stream
+    /// has been shut down.
+    ///
+    /// If an error is returned, the encoder is poisoned, and every further
+    /// write fails.
+    pub fn shutdown_with<W, F>(&mut self, inner: &mut W, shutdown_write: F) -> io::Result<bool>
+    where F: FnOnce(&mut W) -> io::Result<()> {
+        try!(self.poison.check());
+        if self.closed && !self.shut_down && self.outbound_queue.is_empty() {
+            let result = shutdown_write(inner);
+            try!(self.poison.record(result));
+            self.shut_down = true;
+        }
+        Ok(self.shut_down)
+    }
+}
+
+/// Returns the length of the segment table for a message with the provided
+/// number of segments.
+fn segment_table_len(segment_count: usize) -> usize {
+    (segment_count / 2 + 1) * 8
+}
+
+/// Returns the length of a serialized message with the provided segments.
+fn message_len(segments: &[&[u8]]) -> usize {
+    segments.iter().fold(segment_table_len(segments.len()), |acc, segment| acc + segment.len())
+}
+
+/// Serializes the segment table for the provided segments, and appends it to
+/// `segment_table`.
+pub fn serialize_segment_table(segment_table: &mut Vec<u8>, segments: &[&[u8]]) {
+    let mut buf: [u8; 4] = [0; 4];
+
+    <LittleEndian as ByteOrder>::write_u32(&mut buf[..], segments.len() as u32 - 1);
+    segment_table.extend(&buf);
+
+    for segment in segments {
+        <LittleEndian as ByteOrder>::write_u32(&mut buf[..], (segment.len() / 8) as u32);
+        segment_table.extend(&buf);
+    }
+
+    if segments.len() % 2 == 0 {
+        segment_table.extend(&[0, 0, 0, 0]);
+    }
+}
+
+/// Appends the remaining bytes of a message's segment table and segments to
+/// `slices`, beginning at the provided write progress.
+fn message_slices<'a>(slices: &mut Vec<IoSlice<'a>>,
+                      segment_table: &'a [u8],
+                      segments: &[&'a [u8]],
+                      write_progress: (usize, usize)) {
+    let (segment_index, segment_offset) = write_progress;
+    if segment_index == 0 {
+        slices.push(IoSlice::new(&segment_table[segment_offset..]));
+    }
+    for (i, segment) in segments.iter().enumerate().skip(cmp::max(segment_index, 1) - 1) {
+        let offset = if i + 1 == segment_index { segment_offset } else { 0 };
+        let bytes = &segment[offset..];
+        if !bytes.is_empty() {
+            slices.push(IoSlice::new(bytes));
+        }
+    }
+}
+
+/// Writes the slices to the stream in a single vectored write, and returns the
+/// number of bytes written.
+fn write_slices<W>(write: &mut W, slices: &[IoSlice]) -> io::Result<usize> where W: io::Write {
+    loop {
+        match write.write_vectored(slices) {
+            Ok(0) => return result::Result::Err(io::Error::new(io::ErrorKind::WriteZero,
+                                                                "failed to write whole message")),
+            Ok(n) => return Ok(n),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Advances the write progress of a message by up to `n` bytes, and decrements
+/// `n` by the number of bytes consumed. Returns `true` if the message has been
+/// completely written.
+fn advance(segment_table_len: usize,
+           segments: &[&[u8]],
+           write_progress: &mut (usize, usize),
+           n: &mut usize)
+           -> bool {
+    let (ref mut segment_index, ref mut segment_offset) = *write_progress;
+    while *segment_index <= segments.len() {
+        let len = if *segment_index == 0 {
+            segment_table_len
+        } else {
+            segments[*segment_index - 1].len()
+        };
+        let remaining = len - *segment_offset;
+        if *n < remaining {
+            *segment_offset += *n;
+            *n = 0;
+            return false;
+        }
+        *n -= remaining;
+        *segment_offset = 0;
+        *segment_index += 1;
+    }
+    true
+}
+
+/// Writes a message to the stream, beginning at the provided write progress.
+#[cfg(test)]
+pub fn write_message<W>(write: &mut W,
+                        segment_table: &[u8],
+                        segments: &[&[u8]],
+                        write_progress: &mut (usize, usize))
+                        -> io::Result<()>
+where W: io::Write {
+    loop {
+        let mut slices = Vec::new();
+        message_slices(&mut slices, segment_table, segments, *write_progress);
+        let mut n = try!(write_slices(write, &slices));
+        if advance(segment_table.len(), segments, write_progress, &mut n) {
+            return Ok(());
+        }
+    }
+}